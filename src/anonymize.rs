@@ -0,0 +1,208 @@
+use std::{path::Path, sync::Mutex};
+
+use serde_json::Value;
+
+use crate::{
+    cassette::Cassette,
+    error::PromptError,
+    redact::{RedactionMap, Redactor},
+};
+
+/// Replaces identifying values of a whole session with placeholders that stay the
+/// same across all messages, so that traces can be shared while keeping their
+/// structure. On top of the [`Redactor`] patterns it masks home directories and the
+/// local user and host names.
+#[derive(Debug)]
+pub struct Anonymizer {
+    redactor: Redactor,
+    // Shared by the whole session, unlike the per request map of the redactor.
+    map: Mutex<RedactionMap>,
+}
+
+impl Default for Anonymizer {
+    fn default() -> Self {
+        let mut redactor = Redactor::new()
+            .with_pattern("HOME", r#"(?:/home|/Users)/[^/\s"'`]+"#)
+            .and_then(|r| r.with_pattern("HOME", r#"[A-Za-z]:\\Users\\[^\\\s"'`]+"#))
+            .expect("valid pattern");
+
+        let user = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .ok();
+        let host = std::env::var("HOSTNAME").ok().or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|h| h.trim().to_string())
+        });
+        for (label, value) in [("USER", user), ("HOST", host)] {
+            // Short names like "me" would mask unrelated words.
+            if let Some(value) = value
+                && value.len() >= 3
+            {
+                redactor = redactor
+                    .with_pattern(label, &format!(r"\b{}\b", regex::escape(&value)))
+                    .expect("valid pattern");
+            }
+        }
+
+        Self {
+            redactor,
+            map: Mutex::new(RedactionMap::default()),
+        }
+    }
+}
+
+impl Anonymizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mask `value` itself, e.g. a project or company name.
+    pub fn with_identifier(self, label: &str, value: &str) -> Result<Self, PromptError> {
+        self.with_pattern(label, &regex::escape(value))
+    }
+
+    pub fn with_pattern(mut self, label: &str, pattern: &str) -> Result<Self, PromptError> {
+        self.redactor = self.redactor.with_pattern(label, pattern)?;
+        Ok(self)
+    }
+
+    /// Placeholders handed out so far, keep it private to map traces back.
+    pub fn mapping(&self) -> RedactionMap {
+        self.map.lock().expect("poisoned").clone()
+    }
+
+    pub fn anonymize_text(&self, text: &str) -> String {
+        self.redactor
+            .redact(text, &mut self.map.lock().expect("poisoned"))
+    }
+
+    /// Anonymize every string in `value`, object keys are kept.
+    pub fn anonymize_value(&self, value: &mut Value) {
+        match value {
+            Value::String(s) => *s = self.anonymize_text(s),
+            Value::Array(values) => values.iter_mut().for_each(|v| self.anonymize_value(v)),
+            Value::Object(fields) => fields.values_mut().for_each(|v| self.anonymize_value(v)),
+            _ => {}
+        }
+    }
+
+    pub fn anonymize_cassette(&self, cassette: &Cassette) -> Result<Cassette, PromptError> {
+        let mut value = serde_json::to_value(cassette)?;
+        self.anonymize_value(&mut value);
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Write an anonymized copy of a debug directory to `out_dir`, keeping its layout.
+    /// Json lines are anonymized value by value, other files as text. Returns the
+    /// number of files written.
+    pub async fn export_debug_dir(
+        &self,
+        debug_dir: &Path,
+        out_dir: &Path,
+    ) -> Result<usize, PromptError> {
+        tokio::fs::create_dir_all(out_dir).await?;
+
+        let mut exported = 0;
+        let mut entries = tokio::fs::read_dir(debug_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let out = out_dir.join(entry.file_name());
+            if entry.file_type().await?.is_dir() {
+                exported += Box::pin(self.export_debug_dir(&path, &out)).await?;
+                continue;
+            }
+
+            let content = tokio::fs::read_to_string(&path).await?;
+            let anonymized = match path.extension().and_then(|e| e.to_str()) {
+                Some("json") | Some("jsonl") => self.anonymize_json_lines(&content),
+                _ => self.anonymize_text(&content),
+            };
+            tokio::fs::write(&out, anonymized).await?;
+            exported += 1;
+        }
+
+        Ok(exported)
+    }
+
+    // Dumps and indexes hold one json document per line, pretty files a single one.
+    fn anonymize_json_lines(&self, content: &str) -> String {
+        let lines = content
+            .lines()
+            .map(serde_json::from_str::<Value>)
+            .collect::<Result<Vec<_>, _>>();
+        match lines {
+            Ok(values) => values
+                .into_iter()
+                .map(|mut v| {
+                    self.anonymize_value(&mut v);
+                    v.to_string() + "\n"
+                })
+                .collect(),
+            Err(_) => match serde_json::from_str::<Value>(content) {
+                Ok(mut value) => {
+                    self.anonymize_value(&mut value);
+                    serde_json::to_string_pretty(&value).unwrap_or_default()
+                }
+                Err(_) => self.anonymize_text(content),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_consistent_placeholders() {
+        let anonymizer = Anonymizer::new()
+            .with_identifier("USER", "alicew")
+            .and_then(|a| a.with_identifier("HOST", "devbox42"))
+            .unwrap();
+        let mut transcript = json!({
+            "messages": [
+                {"role": "user", "content": "ssh alicew@devbox42 fails"},
+                {"role": "user", "content": "open /home/alicew/zqx/main.rs on devbox42"},
+                {"role": "assistant", "tool_calls": [{
+                    "id": "call_1",
+                    "function": {
+                        "name": "read",
+                        "arguments": "{\"path\":\"/home/alicew/zqx/main.rs\",\"user\":\"alicew\"}",
+                    },
+                }]},
+            ],
+            "temperature": 0.5,
+            "n": 1,
+        });
+
+        anonymizer.anonymize_value(&mut transcript);
+        assert_eq!(
+            transcript,
+            json!({
+                "messages": [
+                    {"role": "user", "content": "ssh [USER_1]@[HOST_1] fails"},
+                    {"role": "user", "content": "open [HOME_1]/zqx/main.rs on [HOST_1]"},
+                    {"role": "assistant", "tool_calls": [{
+                        "id": "call_1",
+                        "function": {
+                            "name": "read",
+                            "arguments": "{\"path\":\"[HOME_1]/zqx/main.rs\",\"user\":\"[USER_1]\"}",
+                        },
+                    }]},
+                ],
+                "temperature": 0.5,
+                "n": 1,
+            })
+        );
+
+        let mapping = anonymizer.mapping();
+        assert_eq!(mapping.originals.len(), 3);
+        assert_eq!(
+            mapping.restore("[HOME_1] [USER_1] [HOST_1]"),
+            "/home/alicew alicew devbox42"
+        );
+    }
+}
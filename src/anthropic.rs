@@ -0,0 +1,617 @@
+use async_openai::{
+    error::{ApiError, OpenAIError},
+    types::chat::{
+        ChatChoice, ChatChoiceStream, ChatCompletionMessageToolCall,
+        ChatCompletionMessageToolCallChunk, ChatCompletionMessageToolCalls,
+        ChatCompletionRequestAssistantMessageContent,
+        ChatCompletionRequestAssistantMessageContentPart, ChatCompletionRequestMessage,
+        ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+        ChatCompletionResponseMessage, ChatCompletionResponseStream,
+        ChatCompletionStreamResponseDelta, ChatCompletionToolChoiceOption, ChatCompletionTools,
+        CompletionUsage, CreateChatCompletionRequest, CreateChatCompletionResponse,
+        CreateChatCompletionStreamResponse, FinishReason, FunctionCall, FunctionCallStream,
+        FunctionType, PromptTokensDetails, Role, StopConfiguration, ToolChoiceOptions,
+    },
+};
+use chrono::Utc;
+use futures_util::{StreamExt, stream};
+use log::warn;
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::{
+    error::{PromptError, ProviderError},
+    llm::message_text,
+};
+
+pub const ANTHROPIC_API_BASE: &str = "https://api.anthropic.com/v1";
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+// The messages API requires max_tokens.
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+#[derive(Debug, Clone)]
+pub struct AnthropicConfig {
+    pub api_base: String,
+    pub api_key: String,
+    pub version: String,
+}
+
+impl Default for AnthropicConfig {
+    fn default() -> Self {
+        Self {
+            api_base: ANTHROPIC_API_BASE.to_string(),
+            api_key: String::new(),
+            version: ANTHROPIC_VERSION.to_string(),
+        }
+    }
+}
+
+impl AnthropicConfig {
+    pub fn with_api_base(mut self, api_base: &str) -> Self {
+        self.api_base = api_base.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.api_key = api_key;
+        self
+    }
+}
+
+/// Claude messages API client speaking OpenAI chat completion types, so that the rest
+/// of the crate is unaware of the backend.
+#[derive(Debug, Clone)]
+pub struct AnthropicClient {
+    config: AnthropicConfig,
+    http: reqwest::Client,
+}
+
+fn image_block(url: &str) -> Value {
+    match url
+        .strip_prefix("data:")
+        .and_then(|s| s.split_once(";base64,"))
+    {
+        Some((media_type, data)) => json!({
+            "type": "image",
+            "source": {"type": "base64", "media_type": media_type, "data": data},
+        }),
+        None => json!({
+            "type": "image",
+            "source": {"type": "url", "url": url},
+        }),
+    }
+}
+
+fn user_blocks(content: &ChatCompletionRequestUserMessageContent) -> Vec<Value> {
+    match content {
+        ChatCompletionRequestUserMessageContent::Text(t) => {
+            vec![json!({"type": "text", "text": t})]
+        }
+        ChatCompletionRequestUserMessageContent::Array(arr) => arr
+            .iter()
+            .filter_map(|part| match part {
+                ChatCompletionRequestUserMessageContentPart::Text(t) => {
+                    Some(json!({"type": "text", "text": t.text}))
+                }
+                ChatCompletionRequestUserMessageContentPart::ImageUrl(img) => {
+                    Some(image_block(&img.image_url.url))
+                }
+                _ => {
+                    warn!("Dropping a user content part unsupported by anthropic");
+                    None
+                }
+            })
+            .collect(),
+    }
+}
+
+fn assistant_blocks(
+    content: Option<&ChatCompletionRequestAssistantMessageContent>,
+    tool_calls: Option<&Vec<ChatCompletionMessageToolCalls>>,
+) -> Vec<Value> {
+    let mut blocks = match content {
+        Some(ChatCompletionRequestAssistantMessageContent::Text(t)) if !t.is_empty() => {
+            vec![json!({"type": "text", "text": t})]
+        }
+        Some(ChatCompletionRequestAssistantMessageContent::Array(arr)) => arr
+            .iter()
+            .filter_map(|part| match part {
+                ChatCompletionRequestAssistantMessageContentPart::Text(t) => {
+                    Some(json!({"type": "text", "text": t.text}))
+                }
+                ChatCompletionRequestAssistantMessageContentPart::Refusal(_) => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    for call in tool_calls.into_iter().flatten() {
+        let (id, name, input) = match call {
+            ChatCompletionMessageToolCalls::Function(f) => (
+                &f.id,
+                &f.function.name,
+                serde_json::from_str(&f.function.arguments).unwrap_or_else(|_| json!({})),
+            ),
+            ChatCompletionMessageToolCalls::Custom(c) => (
+                &c.id,
+                &c.custom_tool.name,
+                json!({"input": c.custom_tool.input}),
+            ),
+        };
+        blocks.push(json!({"type": "tool_use", "id": id, "name": name, "input": input}));
+    }
+    blocks
+}
+
+/// Translate a chat completion request into a messages API body.
+#[doc(hidden)]
+#[allow(deprecated)]
+pub fn to_messages_request(req: &CreateChatCompletionRequest) -> Value {
+    let mut system = vec![];
+    // (role, blocks), consecutive turns of the same role are merged since tool
+    // results must all sit in the user turn following the tool uses.
+    let mut turns: Vec<(&str, Vec<Value>)> = vec![];
+    for msg in req.messages.iter() {
+        let (role, blocks) = match msg {
+            ChatCompletionRequestMessage::System(_)
+            | ChatCompletionRequestMessage::Developer(_) => {
+                system.push(message_text(msg));
+                continue;
+            }
+            ChatCompletionRequestMessage::User(usr) => ("user", user_blocks(&usr.content)),
+            ChatCompletionRequestMessage::Assistant(ass) => (
+                "assistant",
+                assistant_blocks(ass.content.as_ref(), ass.tool_calls.as_ref()),
+            ),
+            ChatCompletionRequestMessage::Tool(tool) => (
+                "user",
+                vec![json!({
+                    "type": "tool_result",
+                    "tool_use_id": tool.tool_call_id,
+                    "content": message_text(msg),
+                })],
+            ),
+            ChatCompletionRequestMessage::Function(f) => (
+                "user",
+                vec![json!({"type": "text", "text": f.content.clone().unwrap_or_default()})],
+            ),
+        };
+        if blocks.is_empty() {
+            continue;
+        }
+        match turns.last_mut() {
+            Some((last, last_blocks)) if *last == role => last_blocks.extend(blocks),
+            _ => turns.push((role, blocks)),
+        }
+    }
+
+    let mut body = json!({
+        "model": req.model,
+        "max_tokens": req
+            .max_completion_tokens
+            .or(req.max_tokens)
+            .unwrap_or(DEFAULT_MAX_TOKENS),
+        "messages": turns
+            .into_iter()
+            .map(|(role, content)| json!({"role": role, "content": content}))
+            .collect::<Vec<_>>(),
+    });
+    if !system.is_empty() {
+        body["system"] = json!(system.join("\n\n"));
+    }
+    if let Some(temperature) = req.temperature {
+        // Anthropic accepts 0 to 1 only.
+        body["temperature"] = json!(temperature.clamp(0.0, 1.0));
+    }
+    if let Some(top_p) = req.top_p {
+        body["top_p"] = json!(top_p);
+    }
+    match req.stop.as_ref() {
+        Some(StopConfiguration::String(s)) => body["stop_sequences"] = json!([s]),
+        Some(StopConfiguration::StringArray(arr)) => body["stop_sequences"] = json!(arr),
+        None => {}
+    }
+    if let Some(tools) = req.tools.as_ref() {
+        body["tools"] = tools
+            .iter()
+            .map(|tool| {
+                let (name, description, input_schema) = match tool {
+                    ChatCompletionTools::Function(f) => (
+                        &f.function.name,
+                        f.function.description.as_ref(),
+                        f.function
+                            .parameters
+                            .clone()
+                            .unwrap_or_else(|| json!({"type": "object", "properties": {}})),
+                    ),
+                    ChatCompletionTools::Custom(c) => (
+                        &c.custom.name,
+                        c.custom.description.as_ref(),
+                        json!({
+                            "type": "object",
+                            "properties": {"input": {"type": "string"}},
+                            "required": ["input"],
+                        }),
+                    ),
+                };
+                let mut tool = json!({"name": name, "input_schema": input_schema});
+                if let Some(description) = description {
+                    tool["description"] = json!(description);
+                }
+                tool
+            })
+            .collect();
+    }
+    if let Some(choice) = req.tool_choice.as_ref() {
+        body["tool_choice"] = match choice {
+            ChatCompletionToolChoiceOption::Mode(ToolChoiceOptions::None) => {
+                json!({"type": "none"})
+            }
+            ChatCompletionToolChoiceOption::Mode(ToolChoiceOptions::Auto)
+            | ChatCompletionToolChoiceOption::AllowedTools(_) => json!({"type": "auto"}),
+            ChatCompletionToolChoiceOption::Mode(ToolChoiceOptions::Required) => {
+                json!({"type": "any"})
+            }
+            ChatCompletionToolChoiceOption::Function(f) => {
+                json!({"type": "tool", "name": f.function.name})
+            }
+            ChatCompletionToolChoiceOption::Custom(c) => {
+                json!({"type": "tool", "name": c.custom.name})
+            }
+        };
+    }
+    if req.response_format.is_some() {
+        warn!("response_format is not supported by anthropic and is ignored");
+    }
+    body
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+    #[serde(default)]
+    cache_creation_input_tokens: Option<u32>,
+    #[serde(default)]
+    cache_read_input_tokens: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    id: String,
+    model: String,
+    content: Vec<ContentBlock>,
+    stop_reason: Option<String>,
+    usage: MessagesUsage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetail {
+    r#type: String,
+    message: String,
+}
+
+impl From<MessagesResponse> for CreateChatCompletionResponse {
+    #[allow(deprecated)]
+    fn from(resp: MessagesResponse) -> Self {
+        let mut text = vec![];
+        let mut tool_calls = vec![];
+        for block in resp.content {
+            match block {
+                ContentBlock::Text { text: t } => text.push(t),
+                ContentBlock::ToolUse { id, name, input } => tool_calls.push(
+                    ChatCompletionMessageToolCalls::Function(ChatCompletionMessageToolCall {
+                        id,
+                        function: FunctionCall {
+                            name,
+                            arguments: input.to_string(),
+                        },
+                    }),
+                ),
+                ContentBlock::Other => {}
+            }
+        }
+        let finish_reason = resp.stop_reason.as_deref().map(|r| match r {
+            "max_tokens" => FinishReason::Length,
+            "tool_use" => FinishReason::ToolCalls,
+            "refusal" => FinishReason::ContentFilter,
+            _ => FinishReason::Stop,
+        });
+        let cached = resp.usage.cache_read_input_tokens.unwrap_or_default();
+        // OpenAI counts cached tokens as part of the prompt, Anthropic does not.
+        let prompt_tokens = resp.usage.input_tokens
+            + cached
+            + resp.usage.cache_creation_input_tokens.unwrap_or_default();
+
+        Self {
+            id: resp.id,
+            choices: vec![ChatChoice {
+                index: 0,
+                message: ChatCompletionResponseMessage {
+                    content: if text.is_empty() {
+                        None
+                    } else {
+                        Some(text.join("\n"))
+                    },
+                    refusal: None,
+                    tool_calls: if tool_calls.is_empty() {
+                        None
+                    } else {
+                        Some(tool_calls)
+                    },
+                    annotations: None,
+                    role: Role::Assistant,
+                    function_call: None,
+                    audio: None,
+                },
+                finish_reason,
+                logprobs: None,
+            }],
+            created: Utc::now().timestamp() as u32,
+            model: resp.model,
+            service_tier: None,
+            system_fingerprint: None,
+            object: "chat.completion".to_string(),
+            usage: Some(CompletionUsage {
+                prompt_tokens,
+                completion_tokens: resp.usage.output_tokens,
+                total_tokens: prompt_tokens + resp.usage.output_tokens,
+                prompt_tokens_details: Some(PromptTokensDetails {
+                    audio_tokens: None,
+                    cached_tokens: Some(cached),
+                }),
+                completion_tokens_details: None,
+            }),
+        }
+    }
+}
+
+// The whole response as a single chunk.
+#[allow(deprecated)]
+pub(crate) fn to_chunk(resp: CreateChatCompletionResponse) -> CreateChatCompletionStreamResponse {
+    CreateChatCompletionStreamResponse {
+        id: resp.id,
+        choices: resp
+            .choices
+            .into_iter()
+            .map(|choice| ChatChoiceStream {
+                index: choice.index,
+                delta: ChatCompletionStreamResponseDelta {
+                    content: choice.message.content,
+                    function_call: None,
+                    tool_calls: choice.message.tool_calls.map(|calls| {
+                        calls
+                            .into_iter()
+                            .enumerate()
+                            .filter_map(|(idx, call)| match call {
+                                ChatCompletionMessageToolCalls::Function(f) => {
+                                    Some(ChatCompletionMessageToolCallChunk {
+                                        index: idx as u32,
+                                        id: Some(f.id),
+                                        r#type: Some(FunctionType::Function),
+                                        function: Some(FunctionCallStream {
+                                            name: Some(f.function.name),
+                                            arguments: Some(f.function.arguments),
+                                        }),
+                                    })
+                                }
+                                ChatCompletionMessageToolCalls::Custom(_) => None,
+                            })
+                            .collect()
+                    }),
+                    role: Some(Role::Assistant),
+                    refusal: choice.message.refusal,
+                },
+                finish_reason: choice.finish_reason,
+                logprobs: None,
+            })
+            .collect(),
+        created: resp.created,
+        model: resp.model,
+        service_tier: None,
+        system_fingerprint: None,
+        object: "chat.completion.chunk".to_string(),
+        usage: resp.usage,
+    }
+}
+
+impl AnthropicClient {
+    pub fn new(config: AnthropicConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn create_chat(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        let resp = self
+            .http
+            .post(format!("{}/messages", self.config.api_base))
+            .header("x-api-key", &self.config.api_key)
+            .header("anthropic-version", &self.config.version)
+            .headers(headers)
+            .json(&to_messages_request(&req))
+            .send()
+            .await
+            .map_err(OpenAIError::from)?;
+        let status = resp.status();
+        let header = |name: &str| {
+            resp.headers()
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let retry_after = header("retry-after");
+        let request_id = header("request-id");
+        let body = resp.text().await.map_err(OpenAIError::from)?;
+
+        if !status.is_success() {
+            let (r#type, mut message) = match serde_json::from_str::<ErrorBody>(&body) {
+                Ok(err) => (err.error.r#type, err.error.message),
+                Err(_) => (status.to_string(), body.clone()),
+            };
+            let code = if r#type == "rate_limit_error" {
+                // Phrased like OpenAI so that the rate limit parsing picks the hint up.
+                if let Some(secs) = retry_after {
+                    message = format!("{} Please try again in {}s.", message, secs);
+                }
+                "rate_limit_exceeded".to_string()
+            } else {
+                r#type.clone()
+            };
+            return Err(PromptError::Provider(Box::new(ProviderError {
+                status: Some(status.as_u16()),
+                request_id,
+                body,
+                source: OpenAIError::ApiError(ApiError {
+                    message,
+                    r#type: Some(r#type),
+                    param: None,
+                    code: Some(code),
+                }),
+            })));
+        }
+
+        serde_json::from_str::<MessagesResponse>(&body)
+            .map(CreateChatCompletionResponse::from)
+            .map_err(|e| PromptError::from_provider(OpenAIError::JSONDeserialize(e, body)))
+    }
+
+    /// Not streamed on the wire, the whole response arrives as one chunk.
+    pub async fn create_chat_stream(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> Result<ChatCompletionResponseStream, PromptError> {
+        let resp = self.create_chat(req, headers).await?;
+        Ok(stream::once(async move { Ok(to_chunk(resp)) }).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(body: Value) -> CreateChatCompletionRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn test_system_and_turns() {
+        let req = request(json!({
+            "model": "claude-haiku-4-5",
+            "messages": [
+                {"role": "system", "content": "sys one"},
+                {"role": "developer", "content": [{"type": "text", "text": "sys two"}]},
+                {"role": "user", "content": "hi"},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "look"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}},
+                ]},
+            ],
+        }));
+        let body = to_messages_request(&req);
+        assert_eq!(body["system"], json!("sys one\n\nsys two"));
+        assert_eq!(body["max_tokens"], json!(DEFAULT_MAX_TOKENS));
+        let messages = body["messages"].as_array().unwrap();
+        // Consecutive user messages are merged into one turn.
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0]["content"],
+            json!([
+                {"type": "text", "text": "hi"},
+                {"type": "text", "text": "look"},
+                {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "AAAA"}},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_tool_use_and_result() {
+        let req = request(json!({
+            "model": "claude-haiku-4-5",
+            "max_completion_tokens": 100,
+            "temperature": 1.5,
+            "messages": [
+                {"role": "user", "content": "weather?"},
+                {"role": "assistant", "content": "", "tool_calls": [
+                    {"id": "call_1", "type": "function",
+                     "function": {"name": "weather", "arguments": "{\"city\":\"Paris\"}"}},
+                ]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "sunny"},
+            ],
+            "tools": [{"type": "function", "function": {"name": "weather"}}],
+            "tool_choice": "required",
+        }));
+        let body = to_messages_request(&req);
+        assert_eq!(body["max_tokens"], json!(100));
+        assert_eq!(body["temperature"], json!(1.0));
+        assert_eq!(body["tool_choice"], json!({"type": "any"}));
+        assert_eq!(
+            body["tools"][0]["input_schema"],
+            json!({"type": "object", "properties": {}})
+        );
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        // Empty assistant text is dropped, only the tool use is kept.
+        assert_eq!(
+            messages[1]["content"],
+            json!([{"type": "tool_use", "id": "call_1", "name": "weather", "input": {"city": "Paris"}}])
+        );
+        assert_eq!(
+            messages[2],
+            json!({"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "call_1", "content": "sunny"},
+            ]})
+        );
+    }
+
+    #[test]
+    fn test_response_conversion() {
+        let resp: MessagesResponse = serde_json::from_value(json!({
+            "id": "msg_1",
+            "model": "claude-haiku-4-5",
+            "content": [
+                {"type": "thinking", "thinking": "hmm"},
+                {"type": "text", "text": "checking"},
+                {"type": "tool_use", "id": "tu_1", "name": "weather", "input": {"city": "Paris"}},
+            ],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 10, "output_tokens": 5, "cache_read_input_tokens": 20},
+        }))
+        .unwrap();
+        let resp = CreateChatCompletionResponse::from(resp);
+        let choice = &resp.choices[0];
+        assert_eq!(choice.message.content.as_deref(), Some("checking"));
+        assert_eq!(choice.finish_reason, Some(FinishReason::ToolCalls));
+        assert_eq!(choice.message.tool_calls.as_ref().map(Vec::len), Some(1));
+        let usage = resp.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 30);
+        assert_eq!(usage.total_tokens, 35);
+        assert_eq!(usage.prompt_tokens_details.unwrap().cached_tokens, Some(20));
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt::Debug;
+
+use async_openai::{
+    Client,
+    config::Config,
+    error::OpenAIError,
+    traits::RequestOptionsBuilder,
+    types::{
+        chat::{
+            ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionResponse,
+        },
+        embeddings::{CreateEmbeddingRequest, CreateEmbeddingResponse},
+    },
+};
+pub use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, StreamExt, stream};
+pub use reqwest::header::HeaderMap;
+
+use crate::{
+    anthropic::{AnthropicClient, to_chunk},
+    cassette::CassetteClient,
+    error::PromptError,
+    replay::ReplayClient,
+};
+
+/// A provider serving chat completions, plug your own with
+/// [`crate::llm::LLMClient::Custom`] for mocks or unsupported providers.
+pub trait ChatBackend: Debug + Send + Sync {
+    /// Errors should carry what the provider answered, see [`PromptError::Provider`].
+    fn create_chat(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> BoxFuture<'_, Result<CreateChatCompletionResponse, PromptError>>;
+
+    /// Defaults to the whole response of [`ChatBackend::create_chat`] as one chunk.
+    fn create_chat_stream(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> BoxFuture<'_, Result<ChatCompletionResponseStream, PromptError>> {
+        async move {
+            let resp = self.create_chat(req, headers).await?;
+            Ok(stream::once(async move { Ok(to_chunk(resp)) }).boxed())
+        }
+        .boxed()
+    }
+
+    fn create_embedding(
+        &self,
+        _req: CreateEmbeddingRequest,
+    ) -> BoxFuture<'_, Result<CreateEmbeddingResponse, OpenAIError>> {
+        async move {
+            Err(OpenAIError::InvalidArgument(
+                "embeddings are not supported by this backend".to_string(),
+            ))
+        }
+        .boxed()
+    }
+}
+
+impl<C: Config + Debug> ChatBackend for Client<C> {
+    fn create_chat(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> BoxFuture<'_, Result<CreateChatCompletionResponse, PromptError>> {
+        async move {
+            self.chat()
+                .headers(headers)
+                .create(req)
+                .await
+                .map_err(PromptError::from_provider)
+        }
+        .boxed()
+    }
+
+    fn create_chat_stream(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> BoxFuture<'_, Result<ChatCompletionResponseStream, PromptError>> {
+        async move {
+            self.chat()
+                .headers(headers)
+                .create_stream(req)
+                .await
+                .map_err(PromptError::from_provider)
+        }
+        .boxed()
+    }
+
+    fn create_embedding(
+        &self,
+        req: CreateEmbeddingRequest,
+    ) -> BoxFuture<'_, Result<CreateEmbeddingResponse, OpenAIError>> {
+        async move { self.embeddings().create(req).await }.boxed()
+    }
+}
+
+impl ChatBackend for AnthropicClient {
+    fn create_chat(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> BoxFuture<'_, Result<CreateChatCompletionResponse, PromptError>> {
+        AnthropicClient::create_chat(self, req, headers).boxed()
+    }
+
+    fn create_embedding(
+        &self,
+        _req: CreateEmbeddingRequest,
+    ) -> BoxFuture<'_, Result<CreateEmbeddingResponse, OpenAIError>> {
+        async move {
+            Err(OpenAIError::InvalidArgument(
+                "anthropic has no embeddings api".to_string(),
+            ))
+        }
+        .boxed()
+    }
+}
+
+impl ChatBackend for ReplayClient {
+    fn create_chat(
+        &self,
+        req: CreateChatCompletionRequest,
+        _headers: HeaderMap,
+    ) -> BoxFuture<'_, Result<CreateChatCompletionResponse, PromptError>> {
+        ReplayClient::create_chat(self, req).boxed()
+    }
+
+    fn create_embedding(
+        &self,
+        _req: CreateEmbeddingRequest,
+    ) -> BoxFuture<'_, Result<CreateEmbeddingResponse, OpenAIError>> {
+        async move {
+            Err(OpenAIError::InvalidArgument(
+                "embeddings are not recorded for replay".to_string(),
+            ))
+        }
+        .boxed()
+    }
+}
+
+impl ChatBackend for CassetteClient {
+    fn create_chat(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> BoxFuture<'_, Result<CreateChatCompletionResponse, PromptError>> {
+        CassetteClient::create_chat(self, req, headers).boxed()
+    }
+
+    fn create_chat_stream(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> BoxFuture<'_, Result<ChatCompletionResponseStream, PromptError>> {
+        CassetteClient::create_chat_stream(self, req, headers).boxed()
+    }
+
+    fn create_embedding(
+        &self,
+        req: CreateEmbeddingRequest,
+    ) -> BoxFuture<'_, Result<CreateEmbeddingResponse, OpenAIError>> {
+        self.inner.backend().create_embedding(req)
+    }
+}
@@ -0,0 +1,208 @@
+use std::{sync::Arc, time::Duration};
+
+use async_openai::types::chat::{
+    CreateChatCompletionRequest, CreateChatCompletionResponse, CreateChatCompletionStreamResponse,
+};
+use futures_util::StreamExt;
+use log::warn;
+use tokio::sync::{Notify, broadcast, watch};
+
+use crate::{
+    error::PromptError,
+    llm::{LLMInner, StreamAcc},
+    redact::DeltaRestorer,
+};
+
+#[derive(Debug, Clone)]
+pub struct BroadcastOptions {
+    /// Chunks buffered for the slowest subscriber.
+    pub capacity: usize,
+    /// Wait for the slowest subscriber when the buffer is full instead of letting it
+    /// skip chunks.
+    pub lossless: bool,
+    /// Longest wait on a full buffer before giving up on lossless delivery, so a
+    /// subscriber that stopped reading, or is never polled, cannot hang the stream.
+    pub stall_timeout: Duration,
+}
+
+impl Default for BroadcastOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            lossless: true,
+            stall_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+type Chunk = Arc<CreateChatCompletionStreamResponse>;
+
+/// Streams one completion to several subscribers as the chunks arrive. Subscribe
+/// first, then [`ChunkBroadcast::run`].
+#[derive(Debug)]
+pub struct ChunkBroadcast<'a> {
+    llm: &'a LLMInner,
+    options: BroadcastOptions,
+    tx: broadcast::Sender<Chunk>,
+    done: watch::Sender<Option<Arc<CreateChatCompletionResponse>>>,
+    received: Arc<Notify>,
+}
+
+#[derive(Debug)]
+pub struct ChunkSubscriber {
+    rx: broadcast::Receiver<Chunk>,
+    done: watch::Receiver<Option<Arc<CreateChatCompletionResponse>>>,
+    received: Arc<Notify>,
+}
+
+impl ChunkSubscriber {
+    /// Next chunk, None once the stream ends or fails.
+    pub async fn recv(&mut self) -> Option<Chunk> {
+        loop {
+            match self.rx.recv().await {
+                Ok(chunk) => {
+                    self.received.notify_one();
+                    return Some(chunk);
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Stream subscriber lagged behind, {} chunks skipped", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// The assembled response after the stream ends, None if it failed.
+    pub fn response(&self) -> Option<Arc<CreateChatCompletionResponse>> {
+        self.done.borrow().clone()
+    }
+}
+
+impl<'a> ChunkBroadcast<'a> {
+    pub fn subscribe(&self) -> ChunkSubscriber {
+        ChunkSubscriber {
+            rx: self.tx.subscribe(),
+            done: self.done.subscribe(),
+            received: self.received.clone(),
+        }
+    }
+
+    /// Stream `req` to all subscribers and return the assembled response. The request
+    /// is billed like [`LLMInner::complete_stream`]. Subscribers must be polled on other
+    /// tasks, lossless delivery otherwise stalls for [`BroadcastOptions::stall_timeout`].
+    pub async fn run(
+        self,
+        req: CreateChatCompletionRequest,
+        prefix: Option<&str>,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        let capacity = self.options.capacity.max(1);
+        let mut lossless = self.options.lossless;
+        let (mut stream, redactions) = self.llm.stream_chunks(req, prefix).await?;
+        let mut restorer = redactions.map(DeltaRestorer::new);
+        let mut acc = StreamAcc::default();
+        while let Some(chunk) = stream.next().await {
+            let mut chunk = chunk?;
+            acc.push(chunk.clone());
+            if let Some(restorer) = restorer.as_mut() {
+                restorer.restore_chunk(&mut chunk);
+            }
+            if lossless {
+                loop {
+                    let received = self.received.notified();
+                    if self.tx.len() < capacity {
+                        break;
+                    }
+                    if tokio::time::timeout(self.options.stall_timeout, received)
+                        .await
+                        .is_err()
+                    {
+                        warn!(
+                            "Stream subscriber stalled for {:?}, slow subscribers skip chunks from now on",
+                            self.options.stall_timeout
+                        );
+                        lossless = false;
+                        break;
+                    }
+                }
+            }
+            // No subscriber is fine.
+            let _ = self.tx.send(Arc::new(chunk));
+        }
+
+        let mut resp = acc.finish(false, &self.llm.model.to_string());
+        if let Some(restorer) = restorer.as_ref() {
+            restorer.map().restore_response(&mut resp);
+        }
+        self.done.send_replace(Some(Arc::new(resp.clone())));
+        Ok(resp)
+    }
+}
+
+impl LLMInner {
+    pub fn broadcast(&self, options: BroadcastOptions) -> ChunkBroadcast<'_> {
+        let (tx, _) = broadcast::channel(options.capacity.max(1));
+        let (done, _) = watch::channel(None);
+        ChunkBroadcast {
+            llm: self,
+            options,
+            tx,
+            done,
+            received: Arc::new(Notify::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockBackend, mock_llm, user_request};
+
+    async fn drain(mut sub: ChunkSubscriber) -> String {
+        let mut content = String::new();
+        while let Some(chunk) = sub.recv().await {
+            for choice in chunk.choices.iter() {
+                content.push_str(choice.delta.content.as_deref().unwrap_or_default());
+            }
+        }
+        content
+    }
+
+    #[tokio::test]
+    async fn test_lossless_backpressure() {
+        let pieces: Vec<_> = (0..20).map(|i| format!("{} ", i)).collect();
+        let pieces: Vec<_> = pieces.iter().map(String::as_str).collect();
+        let llm = mock_llm(Arc::new(MockBackend::new(&pieces)), &[]);
+        let broadcast = llm.broadcast(BroadcastOptions {
+            capacity: 1,
+            ..Default::default()
+        });
+        let first = tokio::spawn(drain(broadcast.subscribe()));
+        let second = tokio::spawn(drain(broadcast.subscribe()));
+
+        let resp = broadcast.run(user_request("count"), None).await.unwrap();
+        let expected = pieces.concat();
+        assert_eq!(resp.choices[0].message.content.as_deref(), Some(&*expected));
+        assert_eq!(first.await.unwrap(), expected);
+        assert_eq!(second.await.unwrap(), expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_subscriber() {
+        let llm = mock_llm(Arc::new(MockBackend::new(&["a", "b", "c", "d"])), &[]);
+        let broadcast = llm.broadcast(BroadcastOptions {
+            capacity: 1,
+            stall_timeout: Duration::from_secs(1),
+            ..Default::default()
+        });
+        // Never polled while the stream runs.
+        let stalled = broadcast.subscribe();
+
+        let started = tokio::time::Instant::now();
+        let resp = broadcast.run(user_request("letters"), None).await.unwrap();
+        assert_eq!(resp.choices[0].message.content.as_deref(), Some("abcd"));
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+        assert!(stalled.response().is_some());
+        // It only kept what fits in the buffer.
+        assert!(drain(stalled).await.len() < 4);
+    }
+}
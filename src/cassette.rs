@@ -0,0 +1,346 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use async_openai::types::chat::{
+    ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionResponse,
+};
+use color_eyre::eyre::eyre;
+use futures_util::{StreamExt, stream};
+use log::{debug, warn};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    anthropic::to_chunk,
+    error::PromptError,
+    llm::{LLMClient, StreamAcc},
+    replay::request_key,
+};
+
+/// How a replayed request picks its recorded interaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMatch {
+    /// The whole request body is equal.
+    Exact,
+    /// Same messages and tools, as keyed by the replay client.
+    Messages,
+    /// The next interaction in recording order, whatever the request.
+    Sequential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    Record,
+    Replay(CassetteMatch),
+    /// Replay by messages if the cassette exists, record it otherwise.
+    Auto,
+}
+
+impl FromStr for CassetteMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "record" => Ok(Self::Record),
+            "exact" => Ok(Self::Replay(CassetteMatch::Exact)),
+            "messages" | "replay" => Ok(Self::Replay(CassetteMatch::Messages)),
+            "sequential" => Ok(Self::Replay(CassetteMatch::Sequential)),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!("unknown cassette mode {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: CreateChatCompletionRequest,
+    pub response: CreateChatCompletionResponse,
+}
+
+/// All interactions of a run in one json file, unlike the per call debug dumps.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load(path: &Path) -> Result<Self, PromptError> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<(), PromptError> {
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec_pretty(self)?).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct CassetteState {
+    cassette: Cassette,
+    used: Vec<bool>,
+}
+
+/// Wraps a client to record every interaction into a cassette, or serves a recorded
+/// cassette without calling the provider.
+#[derive(Debug, Clone)]
+pub struct CassetteClient {
+    pub(crate) inner: Box<LLMClient>,
+    path: PathBuf,
+    mode: CassetteMode,
+    state: Arc<Mutex<CassetteState>>,
+}
+
+impl CassetteClient {
+    pub fn new(inner: LLMClient, path: &Path, mode: CassetteMode) -> Result<Self, PromptError> {
+        let mode = match mode {
+            CassetteMode::Auto if path.exists() => CassetteMode::Replay(CassetteMatch::Messages),
+            CassetteMode::Auto => CassetteMode::Record,
+            mode => mode,
+        };
+        let cassette = match mode {
+            CassetteMode::Record => Cassette::default(),
+            _ => Cassette::load(path)?,
+        };
+        debug!(
+            "Cassette {:?} in {:?} mode with {} interactions",
+            path,
+            mode,
+            cassette.interactions.len()
+        );
+        Ok(Self {
+            inner: Box::new(inner),
+            path: path.to_path_buf(),
+            mode,
+            state: Arc::new(Mutex::new(CassetteState {
+                used: vec![false; cassette.interactions.len()],
+                cassette,
+            })),
+        })
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    async fn record(
+        &self,
+        request: CreateChatCompletionRequest,
+        response: &CreateChatCompletionResponse,
+    ) -> Result<(), PromptError> {
+        let mut state = self.state.lock().await;
+        state.cassette.interactions.push(Interaction {
+            request,
+            response: response.clone(),
+        });
+        state.used.push(true);
+        // Saved under the lock so concurrent calls never race on the file.
+        state.cassette.save(&self.path).await
+    }
+
+    async fn replay(
+        &self,
+        req: &CreateChatCompletionRequest,
+        matching: CassetteMatch,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        let mut state = self.state.lock().await;
+        let key = request_key(req);
+        let body = serde_json::to_value(req)?;
+        let CassetteState { cassette, used } = &mut *state;
+        let pos = cassette
+            .interactions
+            .iter()
+            .zip(used.iter())
+            .position(|(i, used)| {
+                !used
+                    && match matching {
+                        CassetteMatch::Exact => {
+                            serde_json::to_value(&i.request).is_ok_and(|v| v == body)
+                        }
+                        CassetteMatch::Messages => request_key(&i.request) == key,
+                        CassetteMatch::Sequential => true,
+                    }
+            })
+            .ok_or_else(|| {
+                PromptError::Other(eyre!(
+                    "no {:?} match for the request left in cassette {:?}",
+                    matching,
+                    &self.path
+                ))
+            })?;
+        used[pos] = true;
+        Ok(cassette.interactions[pos].response.clone())
+    }
+
+    pub async fn create_chat(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        match self.mode {
+            CassetteMode::Replay(matching) => self.replay(&req, matching).await,
+            _ => {
+                let resp = self
+                    .inner
+                    .create_chat_with_headers(req.clone(), headers)
+                    .await?;
+                if let Err(e) = self.record(req, &resp).await {
+                    warn!("Fail to record cassette due to {}", e);
+                }
+                Ok(resp)
+            }
+        }
+    }
+
+    /// Recording waits for the whole stream, the response comes back as one chunk.
+    pub async fn create_chat_stream(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> Result<ChatCompletionResponseStream, PromptError> {
+        let resp = match self.mode {
+            CassetteMode::Replay(matching) => self.replay(&req, matching).await?,
+            _ => {
+                let mut stream = self
+                    .inner
+                    .create_chat_stream_with_headers(req.clone(), headers)
+                    .await?;
+                let mut acc = StreamAcc::default();
+                while let Some(chunk) = stream.next().await {
+                    acc.push(chunk?);
+                }
+                let resp = acc.finish(false, &req.model);
+                if let Err(e) = self.record(req, &resp).await {
+                    warn!("Fail to record cassette due to {}", e);
+                }
+                resp
+            }
+        };
+        Ok(stream::once(async move { Ok(to_chunk(resp)) }).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockBackend, response, temp_dir, user_request};
+
+    fn request(content: &str, temperature: f32) -> CreateChatCompletionRequest {
+        let mut req = user_request(content);
+        req.temperature = Some(temperature);
+        req
+    }
+
+    // A cassette answering "one" with "first" and "two" with "second".
+    fn cassette(name: &str) -> PathBuf {
+        let path = temp_dir(name).join("cassette.json");
+        let cassette = Cassette {
+            interactions: vec![
+                Interaction {
+                    request: request("one", 0.0),
+                    response: response("first"),
+                },
+                Interaction {
+                    request: request("two", 0.0),
+                    response: response("second"),
+                },
+            ],
+        };
+        std::fs::write(&path, serde_json::to_vec(&cassette).unwrap()).unwrap();
+        path
+    }
+
+    fn client(path: &Path, mode: CassetteMode) -> CassetteClient {
+        let inner = LLMClient::Custom(Arc::new(MockBackend::new(&["recorded"])));
+        CassetteClient::new(inner, path, mode).unwrap()
+    }
+
+    async fn answer(client: &CassetteClient, req: CreateChatCompletionRequest) -> Option<String> {
+        let resp = client.create_chat(req, HeaderMap::new()).await.ok()?;
+        resp.choices[0].message.content.clone()
+    }
+
+    #[tokio::test]
+    async fn test_replay_exact() {
+        let client = client(
+            &cassette("exact"),
+            CassetteMode::Replay(CassetteMatch::Exact),
+        );
+        // Same messages, different body.
+        assert_eq!(answer(&client, request("two", 1.0)).await, None);
+        assert_eq!(
+            answer(&client, request("two", 0.0)).await.as_deref(),
+            Some("second")
+        );
+        assert_eq!(answer(&client, request("two", 0.0)).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_replay_messages() {
+        let client = client(
+            &cassette("messages"),
+            CassetteMode::Replay(CassetteMatch::Messages),
+        );
+        assert_eq!(
+            answer(&client, request("two", 1.0)).await.as_deref(),
+            Some("second")
+        );
+        assert_eq!(
+            answer(&client, request("one", 1.0)).await.as_deref(),
+            Some("first")
+        );
+        let err = client
+            .create_chat(request("one", 1.0), HeaderMap::new())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no Messages match"));
+    }
+
+    #[tokio::test]
+    async fn test_replay_sequential() {
+        let client = client(
+            &cassette("sequential"),
+            CassetteMode::Replay(CassetteMatch::Sequential),
+        );
+        assert_eq!(
+            answer(&client, request("two", 1.0)).await.as_deref(),
+            Some("first")
+        );
+        assert_eq!(
+            answer(&client, request("anything", 1.0)).await.as_deref(),
+            Some("second")
+        );
+        assert_eq!(answer(&client, request("one", 0.0)).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_auto_mode() {
+        let existing = client(&cassette("auto"), CassetteMode::Auto);
+        assert_eq!(
+            existing.mode(),
+            CassetteMode::Replay(CassetteMatch::Messages)
+        );
+        assert_eq!(
+            answer(&existing, request("one", 1.0)).await.as_deref(),
+            Some("first")
+        );
+
+        let path = temp_dir("auto-new").join("cassette.json");
+        let fresh = client(&path, CassetteMode::Auto);
+        assert_eq!(fresh.mode(), CassetteMode::Record);
+        assert_eq!(
+            answer(&fresh, request("three", 0.0)).await.as_deref(),
+            Some("recorded")
+        );
+        let recorded = Cassette::load(&path).unwrap();
+        assert_eq!(recorded.interactions.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&recorded.interactions[0].request).unwrap(),
+            serde_json::to_value(request("three", 0.0)).unwrap()
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use std::{collections::HashMap, path::Path};
+
+use async_openai::types::chat::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+};
+use serde::Deserialize;
+
+use crate::error::PromptError;
+
+// The subset of ChatGPT's `conversations.json` export we need.
+#[derive(Debug, Deserialize)]
+struct ExportConversation {
+    id: Option<String>,
+    conversation_id: Option<String>,
+    title: Option<String>,
+    create_time: Option<f64>,
+    current_node: Option<String>,
+    mapping: HashMap<String, ExportNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportNode {
+    message: Option<ExportMessage>,
+    parent: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportMessage {
+    author: ExportAuthor,
+    content: ExportContent,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportAuthor {
+    role: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportContent {
+    #[serde(default)]
+    parts: Vec<serde_json::Value>,
+    text: Option<String>,
+}
+
+/// A conversation from a ChatGPT data export, ready to be continued with
+/// [`crate::llm::LLMInner::complete`].
+#[derive(Debug, Clone)]
+pub struct ImportedConversation {
+    pub id: Option<String>,
+    pub title: Option<String>,
+    /// Unix timestamp in seconds.
+    pub create_time: Option<f64>,
+    pub messages: Vec<ChatCompletionRequestMessage>,
+}
+
+impl ExportConversation {
+    // The export is a tree of edits and regenerations, keep the branch ending at
+    // the current node only.
+    fn into_imported(self) -> Result<ImportedConversation, PromptError> {
+        let mut branch = vec![];
+        let mut cursor = self.current_node.clone();
+        while let Some(node) = cursor.as_ref().and_then(|id| self.mapping.get(id)) {
+            if let Some(msg) = node.message.as_ref() {
+                branch.push(msg);
+            }
+            if branch.len() > self.mapping.len() {
+                break;
+            }
+            cursor = node.parent.clone();
+        }
+        branch.reverse();
+
+        let mut messages = vec![];
+        for msg in branch {
+            let text = msg
+                .content
+                .parts
+                .iter()
+                .filter_map(|p| p.as_str())
+                .chain(msg.content.text.as_deref())
+                .collect::<Vec<_>>()
+                .join("\n");
+            if text.trim().is_empty() {
+                continue;
+            }
+            // Tool messages have no matching tool calls in the export, skip them.
+            match msg.author.role.as_str() {
+                "user" => messages.push(
+                    ChatCompletionRequestUserMessageArgs::default()
+                        .content(text)
+                        .build()?
+                        .into(),
+                ),
+                "assistant" => messages.push(
+                    ChatCompletionRequestAssistantMessageArgs::default()
+                        .content(text)
+                        .build()?
+                        .into(),
+                ),
+                "system" => messages.push(
+                    ChatCompletionRequestSystemMessageArgs::default()
+                        .content(text)
+                        .build()?
+                        .into(),
+                ),
+                _ => {}
+            }
+        }
+
+        Ok(ImportedConversation {
+            id: self.conversation_id.or(self.id),
+            title: self.title,
+            create_time: self.create_time,
+            messages,
+        })
+    }
+}
+
+/// Parse the `conversations.json` of a ChatGPT data export.
+pub fn parse_chatgpt_export(content: &str) -> Result<Vec<ImportedConversation>, PromptError> {
+    let conversations: Vec<ExportConversation> = serde_json::from_str(content)?;
+    conversations
+        .into_iter()
+        .map(|c| c.into_imported())
+        .collect()
+}
+
+pub async fn load_chatgpt_export(path: &Path) -> Result<Vec<ImportedConversation>, PromptError> {
+    let content = tokio::fs::read_to_string(path).await?;
+    parse_chatgpt_export(&content)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::llm::message_text;
+
+    fn node(role: &str, text: &str, parent: Option<&str>, children: &[&str]) -> serde_json::Value {
+        json!({
+            "message": {"author": {"role": role}, "content": {"content_type": "text", "parts": [text]}},
+            "parent": parent,
+            "children": children,
+        })
+    }
+
+    #[test]
+    fn test_branched_conversation() {
+        // The first answer was regenerated, the conversation went on from the second.
+        let export = json!([{
+            "conversation_id": "c1",
+            "title": "Branches",
+            "create_time": 1700000000.5,
+            "current_node": "a2",
+            "mapping": {
+                "root": {"message": null, "parent": null, "children": ["sys"]},
+                "sys": node("system", "Be brief.", Some("root"), &["u1"]),
+                "u1": node("user", "Hi", Some("sys"), &["a1", "a1b"]),
+                "a1": node("assistant", "Hello", Some("u1"), &["u2old"]),
+                "u2old": node("user", "Abandoned", Some("a1"), &[]),
+                "a1b": node("assistant", "Hey there", Some("u1"), &["tool", "u2"]),
+                "tool": node("tool", "search result", Some("a1b"), &[]),
+                "u2": node("user", "Again?", Some("a1b"), &["empty"]),
+                "empty": node("assistant", " ", Some("u2"), &["a2"]),
+                "a2": node("assistant", "Yes", Some("empty"), &[]),
+            },
+        }]);
+
+        let imported = parse_chatgpt_export(&export.to_string()).unwrap();
+        assert_eq!(imported.len(), 1);
+        let conv = &imported[0];
+        assert_eq!(conv.id.as_deref(), Some("c1"));
+        assert_eq!(conv.title.as_deref(), Some("Branches"));
+
+        let turns: Vec<_> = conv
+            .messages
+            .iter()
+            .map(|m| {
+                let role = match m {
+                    ChatCompletionRequestMessage::System(_) => "system",
+                    ChatCompletionRequestMessage::User(_) => "user",
+                    ChatCompletionRequestMessage::Assistant(_) => "assistant",
+                    _ => "other",
+                };
+                (role, message_text(m))
+            })
+            .collect();
+        assert_eq!(
+            turns,
+            [
+                ("system", "Be brief.".to_string()),
+                ("user", "Hi".to_string()),
+                ("assistant", "Hey there".to_string()),
+                ("user", "Again?".to_string()),
+                ("assistant", "Yes".to_string()),
+            ]
+        );
+    }
+}
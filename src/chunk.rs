@@ -0,0 +1,122 @@
+/// Split `text` into chunks of at most `max_chars` characters, cutting at paragraph
+/// boundaries first and keeping fenced code blocks whole where possible.
+/// Concatenating the chunks gives back `text` exactly.
+pub fn chunk_text(text: &str, max_chars: usize) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+    let mut chunks = vec![];
+    let mut current = String::new();
+
+    for block in blocks(text) {
+        if current.chars().count() + block.chars().count() <= max_chars {
+            current.push_str(&block);
+            continue;
+        }
+
+        if !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if block.chars().count() <= max_chars {
+            current = block;
+        } else {
+            chunks.extend(split_long(&block, max_chars));
+        }
+    }
+
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Split off the trailing whitespace of a chunk so it can be re-attached after
+/// the chunk went through the model.
+pub fn split_trailing_whitespace(chunk: &str) -> (&str, &str) {
+    let body = chunk.trim_end();
+    (body, &chunk[body.len()..])
+}
+
+// Paragraphs with their trailing blank line, fenced code blocks merged into one block.
+fn blocks(text: &str) -> Vec<String> {
+    let mut blocks: Vec<String> = vec![];
+    let mut in_fence = false;
+    for para in text.split_inclusive("\n\n") {
+        match blocks.last_mut() {
+            Some(last) if in_fence => last.push_str(para),
+            _ => blocks.push(para.to_string()),
+        }
+        if para.matches("```").count() % 2 == 1 {
+            in_fence = !in_fence;
+        }
+    }
+    blocks
+}
+
+fn split_long(block: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+    for line in block.split_inclusive('\n') {
+        if current.chars().count() + line.chars().count() > max_chars && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if line.chars().count() > max_chars {
+            let chars = line.chars().collect::<Vec<_>>();
+            for piece in chars.chunks(max_chars) {
+                chunks.push(piece.iter().collect());
+            }
+        } else {
+            current.push_str(line);
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_empty() {
+        assert!(chunk_text("", 10).is_empty());
+    }
+
+    #[test]
+    fn test_chunk_paragraphs() {
+        let text = "first para\n\nsecond para\n\nthird";
+        let chunks = chunk_text(text, 25);
+        assert_eq!(chunks, vec!["first para\n\nsecond para\n\n", "third"]);
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn test_chunk_keeps_fence() {
+        let text = "intro\n\n```\nfn a() {}\n\nfn b() {}\n```\n\nend";
+        let chunks = chunk_text(text, 30);
+        assert_eq!(chunks[1], "```\nfn a() {}\n\nfn b() {}\n```\n\n");
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn test_chunk_long_lines() {
+        let text = "aaaaaaaaaa\nbbb\n";
+        let chunks = chunk_text(text, 4);
+        assert!(chunks.iter().all(|c| c.chars().count() <= 4));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn test_chunk_multibyte() {
+        let text = "é".repeat(7);
+        let chunks = chunk_text(&text, 3);
+        assert_eq!(chunks, vec!["ééé", "ééé", "é"]);
+    }
+
+    #[test]
+    fn test_split_trailing_whitespace() {
+        assert_eq!(split_trailing_whitespace("text \n\n"), ("text", " \n\n"));
+        assert_eq!(split_trailing_whitespace("text"), ("text", ""));
+        assert_eq!(split_trailing_whitespace(" \n"), ("", " \n"));
+    }
+}
@@ -0,0 +1,357 @@
+use futures_util::{StreamExt, TryStreamExt, stream};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::PromptError, llm::LLMInner, structured::response_content};
+
+#[derive(Debug, Clone)]
+pub struct DiffSummaryOptions {
+    /// Files larger than this are summarized per group of hunks.
+    pub max_chunk_chars: usize,
+    /// Max concurrent requests while summarizing files.
+    pub concurrency: usize,
+    /// Extra review focus appended to the prompts, e.g. "security".
+    pub focus: Option<String>,
+}
+
+impl Default for DiffSummaryOptions {
+    fn default() -> Self {
+        Self {
+            max_chunk_chars: 12000,
+            concurrency: 4,
+            focus: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileSummary {
+    pub path: String,
+    pub summary: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffSummary {
+    pub overall: String,
+    pub files: Vec<FileSummary>,
+}
+
+/// A single file section of a unified diff.
+#[derive(Debug, Clone)]
+pub struct FileDiff {
+    pub path: String,
+    pub header: String,
+    pub hunks: Vec<String>,
+}
+
+impl FileDiff {
+    /// Hunks grouped to fit `max_chars`, each group prefixed by the file header.
+    pub fn chunks(&self, max_chars: usize) -> Vec<String> {
+        let mut chunks = vec![];
+        let mut current = self.header.clone();
+        for hunk in self.hunks.iter() {
+            if current.len() + hunk.len() > max_chars && current.len() > self.header.len() {
+                chunks.push(std::mem::replace(&mut current, self.header.clone()));
+            }
+            current.push_str(hunk);
+        }
+        chunks.push(current);
+        chunks
+    }
+}
+
+fn diff_path(line: &str) -> Option<String> {
+    let path = if line.starts_with("diff --git ") {
+        line.split_whitespace().last()?
+    } else {
+        line.get(4..)?.split('\t').next()?.trim()
+    };
+    if path == "/dev/null" {
+        return None;
+    }
+    Some(
+        path.strip_prefix("a/")
+            .or_else(|| path.strip_prefix("b/"))
+            .unwrap_or(path)
+            .to_string(),
+    )
+}
+
+// Old and new line counts from a `@@ -a,b +c,d @@` hunk header.
+fn hunk_counts(line: &str) -> (usize, usize) {
+    let mut counts = line.split_whitespace().skip(1).take(2).map(|r| {
+        r[1..]
+            .split_once(',')
+            .map(|(_, cnt)| cnt.parse().unwrap_or(0))
+            .unwrap_or(1)
+    });
+    (
+        counts.next().unwrap_or_default(),
+        counts.next().unwrap_or_default(),
+    )
+}
+
+/// Split a unified diff into per-file sections and hunks.
+pub fn split_diff(diff_text: &str) -> Vec<FileDiff> {
+    let mut files: Vec<FileDiff> = vec![];
+    // Lines left in the current hunk, so that removed lines like `--- x` are not
+    // mistaken for file headers.
+    let (mut old_left, mut new_left) = (0usize, 0usize);
+
+    for line in diff_text.split_inclusive('\n') {
+        if old_left > 0 || new_left > 0 {
+            match line.chars().next() {
+                Some('-') => old_left = old_left.saturating_sub(1),
+                Some('+') => new_left = new_left.saturating_sub(1),
+                Some('\\') => {}
+                _ => {
+                    old_left = old_left.saturating_sub(1);
+                    new_left = new_left.saturating_sub(1);
+                }
+            }
+            if let Some(hunk) = files.last_mut().and_then(|f| f.hunks.last_mut()) {
+                hunk.push_str(line);
+            }
+            continue;
+        }
+
+        // The `---` line directly following a `diff --git` header belongs to it.
+        let awaits_header = files.last().is_some_and(|f| {
+            f.hunks.is_empty()
+                && f.header.starts_with("diff --git ")
+                && !f.header.contains("\n--- ")
+        });
+        if line.starts_with("diff --git ") || (line.starts_with("--- ") && !awaits_header) {
+            files.push(FileDiff {
+                path: String::new(),
+                header: String::new(),
+                hunks: vec![],
+            });
+        }
+
+        let Some(file) = files.last_mut() else {
+            continue;
+        };
+        if line.starts_with("@@") {
+            (old_left, new_left) = hunk_counts(line);
+            file.hunks.push(line.to_string());
+        } else if let Some(hunk) = file.hunks.last_mut() {
+            hunk.push_str(line);
+        } else {
+            if line.starts_with("+++ ") {
+                if let Some(path) = diff_path(line) {
+                    file.path = path;
+                }
+            } else if (line.starts_with("--- ") || line.starts_with("diff --git "))
+                && file.path.is_empty()
+                && let Some(path) = diff_path(line)
+            {
+                file.path = path;
+            }
+            file.header.push_str(line);
+        }
+    }
+    files
+}
+
+impl LLMInner {
+    /// Summarize a large unified diff: each file (or group of hunks) is summarized
+    /// concurrently and the results are merged into an overall review summary.
+    pub async fn summarize_diff(
+        &self,
+        diff_text: &str,
+        options: &DiffSummaryOptions,
+    ) -> Result<DiffSummary, PromptError> {
+        let focus = options
+            .focus
+            .as_ref()
+            .map(|f| format!(" Pay special attention to: {}.", f))
+            .unwrap_or_default();
+        let chunk_sys = format!(
+            "You are reviewing a code change. Summarize what the given diff does, \
+            and point out anything a reviewer should look at closely. Be concise.{}",
+            focus
+        );
+
+        let jobs = split_diff(diff_text)
+            .into_iter()
+            .flat_map(|f| {
+                let path = f.path.clone();
+                f.chunks(options.max_chunk_chars)
+                    .into_iter()
+                    .map(move |c| (path.clone(), c))
+            })
+            .collect::<Vec<_>>();
+
+        let chunk_summaries: Vec<(String, String)> = stream::iter(jobs)
+            .map(|(path, chunk)| {
+                let chunk_sys = &chunk_sys;
+                async move {
+                    let resp = self
+                        .prompt_once_with_retry(chunk_sys, &chunk, Some("diff"), None)
+                        .await?;
+                    Ok::<_, PromptError>((path, response_content(&resp)?))
+                }
+            })
+            .buffered(options.concurrency.max(1))
+            .try_collect()
+            .await?;
+
+        let files = chunk_summaries
+            .into_iter()
+            .chunk_by(|(path, _)| path.clone())
+            .into_iter()
+            .map(|(path, group)| FileSummary {
+                path,
+                summary: group.map(|(_, s)| s).join("\n"),
+            })
+            .collect::<Vec<_>>();
+
+        let merge_sys = format!(
+            "You are reviewing a code change. Given summaries of each changed file, \
+            write an overall review summary: the purpose of the change, the main \
+            modifications and the risks worth a closer look.{}",
+            focus
+        );
+        let merge_user = files
+            .iter()
+            .map(|f| format!("## {}\n{}", f.path, f.summary))
+            .join("\n\n");
+        let resp = self
+            .prompt_once_with_retry(&merge_sys, &merge_user, Some("diff"), None)
+            .await?;
+
+        Ok(DiffSummary {
+            overall: response_content(&resp)?,
+            files,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hunk_counts() {
+        assert_eq!(hunk_counts("@@ -1,3 +1,4 @@ fn main() {"), (3, 4));
+        assert_eq!(hunk_counts("@@ -5 +5 @@"), (1, 1));
+        assert_eq!(hunk_counts("@@ -0,0 +1,2 @@"), (0, 2));
+    }
+
+    #[test]
+    fn test_split_empty() {
+        assert!(split_diff("").is_empty());
+    }
+
+    #[test]
+    fn test_split_multi_file() {
+        let diff = "\
+diff --git a/src/a.rs b/src/a.rs
+index 1111111..2222222 100644
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -1,2 +1,2 @@
+-fn a() {}
++fn a() -> u32 { 1 }
+ fn b() {}
+@@ -10 +10,2 @@
+ x
++y
+diff --git a/src/new.rs b/src/new.rs
+new file mode 100644
+--- /dev/null
++++ b/src/new.rs
+@@ -0,0 +1 @@
++fn new() {}
+";
+        let files = split_diff(diff);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, "src/a.rs");
+        assert_eq!(files[0].hunks.len(), 2);
+        assert!(files[0].header.starts_with("diff --git "));
+        assert!(files[0].header.ends_with("+++ b/src/a.rs\n"));
+        assert_eq!(files[1].path, "src/new.rs");
+        assert_eq!(files[1].hunks, vec!["@@ -0,0 +1 @@\n+fn new() {}\n"]);
+        // Nothing is lost when splitting.
+        let joined: String = files
+            .iter()
+            .map(|f| f.header.clone() + &f.hunks.concat())
+            .collect();
+        assert_eq!(joined, diff);
+    }
+
+    #[test]
+    fn test_split_removed_dashes() {
+        // A removed line starting with "-- " looks like a file header.
+        let diff = "\
+--- a/notes.sql
++++ b/notes.sql
+@@ -1,2 +1 @@
+--- a comment
+ select 1;
+";
+        let files = split_diff(diff);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "notes.sql");
+        assert_eq!(files[0].hunks.len(), 1);
+        assert!(files[0].hunks[0].contains("--- a comment"));
+    }
+
+    #[test]
+    fn test_split_rename() {
+        let diff = "\
+diff --git a/old.rs b/new.rs
+similarity index 100%
+rename from old.rs
+rename to new.rs
+diff --git a/c.rs b/c.rs
+--- a/c.rs
++++ b/c.rs
+@@ -1 +1 @@
+-c
++d
+";
+        let files = split_diff(diff);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, "new.rs");
+        assert!(files[0].hunks.is_empty());
+        assert!(files[0].header.contains("rename to new.rs"));
+        assert_eq!(files[1].path, "c.rs");
+    }
+
+    #[test]
+    fn test_split_no_newline() {
+        let diff = "\
+--- a/a.txt
++++ b/a.txt
+@@ -1 +1 @@
+-old
+\\ No newline at end of file
++new
+\\ No newline at end of file
+--- a/b.txt
++++ b/b.txt
+@@ -1 +1 @@
+-x
++y
+";
+        let files = split_diff(diff);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, "a.txt");
+        assert_eq!(files[0].hunks.len(), 1);
+        assert!(files[0].hunks[0].ends_with("+new\n\\ No newline at end of file\n"));
+        assert_eq!(files[1].path, "b.txt");
+    }
+
+    #[test]
+    fn test_chunks() {
+        let file = FileDiff {
+            path: "a".to_string(),
+            header: "h\n".to_string(),
+            hunks: vec!["1234\n".to_string(), "5678\n".to_string()],
+        };
+        assert_eq!(file.chunks(100), vec!["h\n1234\n5678\n"]);
+        assert_eq!(file.chunks(8), vec!["h\n1234\n", "h\n5678\n"]);
+    }
+}
@@ -0,0 +1,304 @@
+use std::{collections::VecDeque, str::FromStr, time::Duration};
+
+use async_openai::{
+    error::OpenAIError,
+    types::embeddings::{CreateEmbeddingRequest, CreateEmbeddingResponse, EmbeddingInput},
+};
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use derive_more::derive::Display;
+use futures_util::{StreamExt, TryStreamExt, stream};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    error::PromptError,
+    llm::{BillingEntry, LLMClient, LLMInner, billing_tag},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Display)]
+pub enum EmbeddingModel {
+    #[display("text-embedding-3-small")]
+    TextEmbedding3Small,
+    #[display("text-embedding-3-large")]
+    TextEmbedding3Large,
+    #[display("text-embedding-ada-002")]
+    Ada002,
+    /// Name and USD per 1M tokens
+    #[display("{_0}")]
+    Other(String, f64),
+}
+
+impl FromStr for EmbeddingModel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text-embedding-3-small" => Ok(Self::TextEmbedding3Small),
+            "text-embedding-3-large" => Ok(Self::TextEmbedding3Large),
+            "text-embedding-ada-002" | "ada-002" => Ok(Self::Ada002),
+            _ => match s.split_once(",") {
+                Some((name, price)) => Ok(Self::Other(
+                    name.to_string(),
+                    f64::from_str(price).map_err(|e| e.to_string())?,
+                )),
+                None => {
+                    log::info!("No valid embedding model detected, assume not billed");
+                    Ok(Self::Other(s.to_string(), 0.0))
+                }
+            },
+        }
+    }
+}
+
+impl EmbeddingModel {
+    /// USD per 1M input tokens
+    pub fn pricing(&self) -> f64 {
+        match self {
+            Self::TextEmbedding3Small => 0.02,
+            Self::TextEmbedding3Large => 0.13,
+            Self::Ada002 => 0.10,
+            Self::Other(_, pricing) => *pricing,
+        }
+    }
+}
+
+impl LLMClient {
+    pub async fn create_embedding(
+        &self,
+        req: CreateEmbeddingRequest,
+    ) -> Result<CreateEmbeddingResponse, OpenAIError> {
+        self.backend().create_embedding(req).await
+    }
+}
+
+impl LLMInner {
+    /// Embed `texts` with the embedding model, vectors are in the order of `texts`.
+    pub async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, PromptError> {
+        self.throttle_spend().await;
+        let req = CreateEmbeddingRequest {
+            model: self.embedding_model.to_string(),
+            input: EmbeddingInput::StringArray(texts.to_vec()),
+            ..Default::default()
+        };
+        let client = self.embedding_client.as_ref().unwrap_or(&self.client);
+        let mut resp = client.create_embedding(req).await?;
+
+        let tokens = resp.usage.prompt_tokens as u64;
+        let mut billing = self.billing.write().await;
+        let before = billing.current;
+        let billed = billing.embedding_tokens(&self.embedding_model, tokens);
+        let cost = billing.current - before;
+        let entry = BillingEntry {
+            time: Utc::now(),
+            model: self.embedding_model.to_string(),
+            prefix: "embedding".to_string(),
+            tag: billing_tag(),
+            input_tokens: tokens,
+            cached_tokens: 0,
+            output_tokens: 0,
+            reasoning_tokens: 0,
+            tool_tokens: 0,
+            cost,
+            response: None,
+        };
+        self.record_billing(billing, entry).await;
+        debug!("Embedded {} texts with {} tokens", texts.len(), tokens);
+        info!("Model Billing: {}", &self.billing.read().await);
+        billed.map_err(PromptError::Other)?;
+
+        resp.data.sort_by_key(|e| e.index);
+        Ok(resp.data.into_iter().map(|e| e.embedding).collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EmbedOptions {
+    /// Max inputs per request.
+    pub max_batch: usize,
+    /// Max tokens per request.
+    pub max_batch_tokens: usize,
+    /// Tokens per minute limit of the deployment, if any.
+    pub tpm: Option<usize>,
+    /// Attempts per batch before giving up.
+    pub retry: u64,
+    pub concurrency: usize,
+}
+
+impl Default for EmbedOptions {
+    fn default() -> Self {
+        Self {
+            max_batch: 2048,
+            max_batch_tokens: 300_000,
+            tpm: None,
+            retry: 5,
+            concurrency: 4,
+        }
+    }
+}
+
+// Tokens sent during the last minute.
+#[derive(Debug, Default)]
+struct TokenWindow {
+    sent: VecDeque<(Instant, usize)>,
+}
+
+impl TokenWindow {
+    // Time to wait before `tokens` more fit in `tpm`, recording them if they fit now.
+    fn reserve(&mut self, tokens: usize, tpm: usize) -> Option<Duration> {
+        let now = Instant::now();
+        while self
+            .sent
+            .front()
+            .is_some_and(|(t, _)| now.duration_since(*t) >= Duration::from_secs(60))
+        {
+            self.sent.pop_front();
+        }
+        let used: usize = self.sent.iter().map(|(_, n)| n).sum();
+        match self.sent.front() {
+            Some((oldest, _)) if used + tokens > tpm => {
+                Some(Duration::from_secs(60).saturating_sub(now.duration_since(*oldest)))
+            }
+            _ => {
+                self.sent.push_back((now, tokens));
+                None
+            }
+        }
+    }
+}
+
+impl LLMInner {
+    /// Embed many texts in batches within the provider limits, retrying failed batches.
+    /// Vectors are in the order of `texts`.
+    pub async fn embed_many(
+        &self,
+        texts: &[String],
+        options: &EmbedOptions,
+    ) -> Result<Vec<Vec<f32>>, PromptError> {
+        let mut batches: Vec<(Vec<String>, usize)> = vec![];
+        for text in texts.iter() {
+            let tokens = self.token_counter.count(text);
+            match batches.last_mut() {
+                Some((batch, batch_tokens))
+                    if batch.len() < options.max_batch
+                        && *batch_tokens + tokens <= options.max_batch_tokens =>
+                {
+                    batch.push(text.clone());
+                    *batch_tokens += tokens;
+                }
+                _ => batches.push((vec![text.clone()], tokens)),
+            }
+        }
+        debug!(
+            "Embedding {} texts in {} batches",
+            texts.len(),
+            batches.len()
+        );
+
+        let window = Mutex::new(TokenWindow::default());
+        let results: Vec<Vec<Vec<f32>>> = stream::iter(batches)
+            .map(|(batch, tokens)| {
+                let window = &window;
+                async move {
+                    if let Some(tpm) = options.tpm {
+                        // A batch larger than the whole limit still goes out alone.
+                        let tokens = tokens.min(tpm);
+                        loop {
+                            let wait = window.lock().await.reserve(tokens, tpm);
+                            match wait {
+                                Some(wait) => tokio::time::sleep(wait).await,
+                                None => break,
+                            }
+                        }
+                    }
+                    self.embed_with_retry(&batch, options.retry).await
+                }
+            })
+            .buffered(options.concurrency.max(1))
+            .try_collect()
+            .await?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    async fn embed_with_retry(
+        &self,
+        batch: &[String],
+        retry: u64,
+    ) -> Result<Vec<Vec<f32>>, PromptError> {
+        let mut backoff = Duration::from_secs(1);
+        let mut last = None;
+        let attempts = retry.max(1);
+        for idx in 0..attempts {
+            match self.embed(batch).await {
+                Ok(vectors) if vectors.len() == batch.len() => return Ok(vectors),
+                Ok(vectors) => {
+                    warn!(
+                        "Got {} embeddings for {} inputs during {} retry",
+                        vectors.len(),
+                        batch.len(),
+                        idx
+                    );
+                    last = Some(PromptError::Other(eyre!(
+                        "embedding count mismatch: {} for {} inputs",
+                        vectors.len(),
+                        batch.len()
+                    )));
+                }
+                Err(e) => {
+                    warn!("Having an error {} during {} embedding retry", e, idx);
+                    last = Some(e);
+                }
+            }
+            if idx + 1 < attempts {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(30));
+            }
+        }
+        Err(last.unwrap_or_else(|| PromptError::Other(eyre!("retry is zero?!"))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::testing::{MockBackend, mock_llm};
+
+    #[tokio::test]
+    async fn test_embed_many_batches() {
+        let backend = Arc::new(MockBackend::default());
+        let llm = mock_llm(backend.clone(), &[]);
+        let texts: Vec<_> = (1..=7).map(|n| "x".repeat(n)).collect();
+        let options = EmbedOptions {
+            max_batch: 3,
+            concurrency: 2,
+            ..Default::default()
+        };
+
+        let vectors = llm.embed_many(&texts, &options).await.unwrap();
+        assert_eq!(*backend.embed_batches.lock().unwrap(), [3, 3, 1]);
+        let lens: Vec<_> = vectors.iter().map(|v| v[0] as usize).collect();
+        assert_eq!(lens, [1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_embed_retry_no_sleep_after_last() {
+        let backend = Arc::new(MockBackend {
+            embed_fails: true,
+            ..Default::default()
+        });
+        let llm = mock_llm(backend.clone(), &[]);
+        let options = EmbedOptions {
+            retry: 3,
+            ..Default::default()
+        };
+
+        let started = Instant::now();
+        let res = llm.embed_many(&["a".to_string()], &options).await;
+        assert!(res.is_err());
+        assert_eq!(backend.embed_batches.lock().unwrap().len(), 3);
+        // 1s and 2s between the attempts, nothing after the last one.
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+    }
+}
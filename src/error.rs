@@ -1,14 +1,94 @@
-use async_openai::error::OpenAIError;
+use std::{fmt::Display, sync::LazyLock};
+
+use async_openai::error::{ApiError, OpenAIError};
+use regex::Regex;
 use thiserror::Error;
 
+static REQUEST_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)request[ _-]?id:?\s*([A-Za-z0-9_-]+)").expect("valid regex"));
+
+/// What the provider answered when a request failed.
+#[derive(Debug)]
+pub struct ProviderError {
+    pub status: Option<u16>,
+    pub request_id: Option<String>,
+    /// The raw body when it could not be parsed, otherwise the parsed error as json
+    /// since async-openai keeps nothing else.
+    pub body: String,
+    pub source: OpenAIError,
+}
+
+impl Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)?;
+        if let Some(status) = self.status {
+            write!(f, " (status {})", status)?;
+        }
+        if let Some(id) = self.request_id.as_ref() {
+            write!(f, " (request id {})", id)?;
+        }
+        Ok(())
+    }
+}
+
+fn api_error_body(api: &ApiError) -> String {
+    serde_json::json!({
+        "error": {
+            "message": api.message,
+            "type": api.r#type,
+            "param": api.param,
+            "code": api.code,
+        }
+    })
+    .to_string()
+}
+
 #[derive(Error, Debug)]
 pub enum PromptError {
     #[error("io error: {0}")]
     IO(#[from] std::io::Error),
     #[error("openai error: {0}")]
     OpenAI(#[from] OpenAIError),
+    #[error("provider error: {0}")]
+    Provider(Box<ProviderError>),
     #[error("json error: {0}")]
     STDJSON(#[from] serde_json::Error),
     #[error(transparent)]
     Other(#[from] color_eyre::Report),
 }
+
+impl PromptError {
+    /// Wrap an error of a completion call, keeping the provider answer if there is one.
+    pub fn from_provider(err: OpenAIError) -> Self {
+        let body = match &err {
+            OpenAIError::ApiError(api) => api_error_body(api),
+            OpenAIError::JSONDeserialize(_, content) => content.clone(),
+            _ => return Self::OpenAI(err),
+        };
+        let request_id = REQUEST_ID.captures(&body).map(|caps| caps[1].to_string());
+        Self::Provider(Box::new(ProviderError {
+            status: None,
+            request_id,
+            body,
+            source: err,
+        }))
+    }
+
+    /// Back to an [`OpenAIError`] for interfaces that only carry those.
+    pub fn into_openai(self) -> OpenAIError {
+        match self {
+            Self::OpenAI(e) => e,
+            Self::Provider(p) => p.source,
+            e => OpenAIError::InvalidArgument(e.to_string()),
+        }
+    }
+
+    /// The underlying OpenAI error, if any.
+    pub fn openai_error(&self) -> Option<&OpenAIError> {
+        match self {
+            Self::OpenAI(e) => Some(e),
+            Self::Provider(p) => Some(&p.source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::path::Path;
+
+use async_openai::types::chat::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use log::{debug, warn};
+use serde_json::{Value, json};
+
+use crate::{anthropic::to_chunk, error::PromptError, llm::DEBUG_SESSION_FILE};
+
+/// A WireMock stub answering `req` with `resp`. Requests match on model and messages
+/// only; streamed requests get the response as a single server-sent event.
+pub fn wiremock_mapping(
+    req: &CreateChatCompletionRequest,
+    resp: &CreateChatCompletionResponse,
+) -> Result<Value, PromptError> {
+    let response = if req.stream == Some(true) {
+        let chunk = serde_json::to_string(&to_chunk(resp.clone()))?;
+        json!({
+            "status": 200,
+            "headers": {"Content-Type": "text/event-stream"},
+            "body": format!("data: {}\n\ndata: [DONE]\n\n", chunk),
+        })
+    } else {
+        json!({
+            "status": 200,
+            "headers": {"Content-Type": "application/json"},
+            "jsonBody": resp,
+        })
+    };
+
+    Ok(json!({
+        "request": {
+            "method": "POST",
+            "urlPathPattern": ".*/chat/completions",
+            "bodyPatterns": [{
+                "equalToJson": {"model": req.model, "messages": req.messages},
+                "ignoreExtraElements": true,
+            }],
+        },
+        "response": response,
+    }))
+}
+
+/// Convert the json dumps of a debug directory into WireMock mappings under
+/// `out_dir`, one file per completion. Dumps without a response are skipped.
+pub async fn export_wiremock(debug_dir: &Path, out_dir: &Path) -> Result<usize, PromptError> {
+    tokio::fs::create_dir_all(out_dir).await?;
+
+    let mut exported = 0;
+    let mut entries = tokio::fs::read_dir(debug_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json")
+            || entry.file_name() == DEBUG_SESSION_FILE
+        {
+            continue;
+        }
+
+        let content = tokio::fs::read_to_string(&path).await?;
+        let mut lines = content.lines();
+        let (Some(req), Some(resp)) = (lines.next(), lines.next()) else {
+            debug!("No response in {:?}, skipped", &path);
+            continue;
+        };
+        let mapping = match (serde_json::from_str(req), serde_json::from_str(resp)) {
+            (Ok(req), Ok(resp)) => wiremock_mapping(&req, &resp)?,
+            _ => {
+                warn!("Fail to parse debug dump {:?}, skipped", &path);
+                continue;
+            }
+        };
+        tokio::fs::write(
+            out_dir.join(entry.file_name()),
+            serde_json::to_vec_pretty(&mapping)?,
+        )
+        .await?;
+        exported += 1;
+    }
+
+    Ok(exported)
+}
@@ -0,0 +1,206 @@
+use std::path::Path;
+
+use async_openai::types::chat::{
+    CompletionUsage, CreateChatCompletionRequest, CreateChatCompletionResponse, FinishReason,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{error::PromptError, provider::Provider};
+
+/// One completion call, with attribute names from the OpenTelemetry GenAI semantic
+/// conventions so that existing pipelines can ingest the log as is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenAIRecord {
+    pub time: DateTime<Utc>,
+    #[serde(rename = "gen_ai.operation.name")]
+    pub operation_name: String,
+    #[serde(rename = "gen_ai.provider.name")]
+    pub provider_name: String,
+    #[serde(rename = "gen_ai.request.model")]
+    pub request_model: String,
+    #[serde(
+        rename = "gen_ai.request.temperature",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub request_temperature: Option<f32>,
+    #[serde(
+        rename = "gen_ai.request.max_tokens",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub request_max_tokens: Option<u32>,
+    #[serde(
+        rename = "gen_ai.request.presence_penalty",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub request_presence_penalty: Option<f32>,
+    #[serde(rename = "gen_ai.response.id", skip_serializing_if = "Option::is_none")]
+    pub response_id: Option<String>,
+    #[serde(
+        rename = "gen_ai.response.model",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub response_model: Option<String>,
+    #[serde(rename = "gen_ai.response.finish_reasons", default)]
+    pub response_finish_reasons: Vec<String>,
+    #[serde(
+        rename = "gen_ai.usage.input_tokens",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub usage_input_tokens: Option<u32>,
+    #[serde(
+        rename = "gen_ai.usage.output_tokens",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub usage_output_tokens: Option<u32>,
+    #[serde(rename = "error.type", skip_serializing_if = "Option::is_none")]
+    pub error_type: Option<String>,
+    #[serde(rename = "openai_models.prefix")]
+    pub prefix: String,
+    #[serde(rename = "openai_models.cost_usd", default)]
+    pub cost_usd: f64,
+    #[serde(rename = "openai_models.duration_ms", default)]
+    pub duration_ms: u64,
+}
+
+fn provider_name(provider: Provider) -> String {
+    match provider {
+        Provider::Azure => "azure.ai.openai".to_string(),
+        _ => provider.to_string(),
+    }
+}
+
+fn finish_reason_name(reason: &FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Stop => "stop",
+        FinishReason::Length => "length",
+        FinishReason::ToolCalls => "tool_calls",
+        FinishReason::ContentFilter => "content_filter",
+        FinishReason::FunctionCall => "function_call",
+    }
+}
+
+impl GenAIRecord {
+    /// The request part of a record, filled before the request is sent.
+    #[allow(deprecated)]
+    pub fn request(req: &CreateChatCompletionRequest, provider: Provider, prefix: &str) -> Self {
+        Self {
+            time: Utc::now(),
+            operation_name: "chat".to_string(),
+            provider_name: provider_name(provider),
+            request_model: req.model.clone(),
+            request_temperature: req.temperature,
+            request_max_tokens: req.max_completion_tokens.or(req.max_tokens),
+            request_presence_penalty: req.presence_penalty,
+            response_id: None,
+            response_model: None,
+            response_finish_reasons: vec![],
+            usage_input_tokens: None,
+            usage_output_tokens: None,
+            error_type: None,
+            prefix: prefix.to_string(),
+            cost_usd: 0.0,
+            duration_ms: 0,
+        }
+    }
+
+    pub fn finish(
+        &mut self,
+        resp: &CreateChatCompletionResponse,
+        usage: Option<&CompletionUsage>,
+        cost: f64,
+    ) {
+        self.response_id = Some(resp.id.clone());
+        self.response_model = Some(resp.model.clone());
+        self.response_finish_reasons = resp
+            .choices
+            .iter()
+            .filter_map(|c| c.finish_reason.as_ref())
+            .map(|r| finish_reason_name(r).to_string())
+            .collect();
+        self.usage_input_tokens = usage.map(|u| u.prompt_tokens);
+        self.usage_output_tokens = usage.map(|u| u.completion_tokens);
+        self.cost_usd = cost;
+        self.duration_ms = self.elapsed_ms();
+    }
+
+    pub fn fail(&mut self, error: &PromptError) {
+        let error_type = match error {
+            PromptError::IO(_) => "io",
+            PromptError::OpenAI(_) => "openai",
+            PromptError::Provider(_) => "provider",
+            PromptError::STDJSON(_) => "json",
+            PromptError::Other(_) => "other",
+        };
+        self.error_type = Some(error_type.to_string());
+        self.duration_ms = self.elapsed_ms();
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        (Utc::now() - self.time).num_milliseconds().max(0) as u64
+    }
+
+    pub async fn append_to(&self, path: &Path) -> Result<(), PromptError> {
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        let mut fp = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        fp.write_all(line.as_bytes()).await?;
+        fp.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "otel")]
+impl GenAIRecord {
+    /// Emit the record as a client span through the global OpenTelemetry tracer, under
+    /// the current context.
+    pub fn emit_span(&self) {
+        use opentelemetry::{
+            Array, KeyValue, StringValue, Value, global,
+            trace::{Span, SpanKind, Status, Tracer},
+        };
+
+        let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(self) else {
+            return;
+        };
+        let attributes = fields
+            .into_iter()
+            .filter(|(k, _)| k != "time")
+            .filter_map(|(k, v)| {
+                let v = match v {
+                    serde_json::Value::String(s) => Value::from(s),
+                    serde_json::Value::Bool(b) => Value::from(b),
+                    serde_json::Value::Number(n) => match n.as_i64() {
+                        Some(i) => Value::from(i),
+                        None => Value::from(n.as_f64()?),
+                    },
+                    serde_json::Value::Array(vs) => Value::Array(Array::String(
+                        vs.into_iter()
+                            .filter_map(|v| v.as_str().map(|s| StringValue::from(s.to_string())))
+                            .collect(),
+                    )),
+                    _ => return None,
+                };
+                Some(KeyValue::new(k, v))
+            })
+            .collect::<Vec<_>>();
+
+        let start: std::time::SystemTime = self.time.into();
+        let tracer = global::tracer("openai-models");
+        let mut span = tracer
+            .span_builder(format!("{} {}", &self.operation_name, &self.request_model))
+            .with_kind(SpanKind::Client)
+            .with_start_time(start)
+            .with_attributes(attributes)
+            .start(&tracer);
+        if let Some(error_type) = self.error_type.as_ref() {
+            span.set_status(Status::error(error_type.clone()));
+        }
+        span.end_with_timestamp(start + std::time::Duration::from_millis(self.duration_ms));
+    }
+}
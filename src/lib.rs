@@ -4,13 +4,72 @@ use chrono::NaiveDate;
 use derive_more::derive::Display;
 use serde::{Deserialize, Serialize};
 
+pub mod anonymize;
+pub mod broadcast;
+pub mod chatgpt;
+pub mod chunk;
+pub mod diff;
+pub mod embeddings;
 pub mod error;
 pub mod llm;
+pub mod memory;
+pub mod models;
+pub mod pricing;
+pub mod redact;
+pub mod slots;
+pub mod stats;
+pub mod structured;
+pub mod tokens;
+pub mod vote;
+
+pub(crate) mod anthropic;
+pub(crate) mod backend;
+pub(crate) mod cassette;
+pub(crate) mod fixtures;
+pub(crate) mod genai;
+#[cfg(feature = "metrics")]
+pub(crate) mod metrics;
+pub(crate) mod provider;
+pub(crate) mod ratelimit;
+pub(crate) mod replay;
+pub(crate) mod rewrite;
+#[cfg(test)]
+mod testing;
+
+pub use crate::{
+    anthropic::{AnthropicClient, AnthropicConfig},
+    backend::{BoxFuture, ChatBackend, HeaderMap},
+    cassette::{Cassette, CassetteClient, CassetteMatch, CassetteMode, Interaction},
+    fixtures::{export_wiremock, wiremock_mapping},
+    genai::GenAIRecord,
+    provider::{Provider, ProviderQuirks, RoleMapping},
+    ratelimit::{RateLimitInfo, SpendLimiter},
+    replay::ReplayClient,
+};
+
+#[cfg(feature = "metrics")]
+pub use crate::metrics::describe as describe_metrics;
 
 pub mod openai {
     pub use async_openai::*;
 }
 
+/// The stable surface meant for users, `use openai_models::prelude::*;`.
+pub mod prelude {
+    pub use crate::{
+        ChatBackend, ModelInfo, OpenAIModel, PricingInfo, Provider, RateLimitInfo,
+        embeddings::{EmbedOptions, EmbeddingModel},
+        error::PromptError,
+        llm::{
+            LLM, LLMClient, LLMInner, LLMSettings, ModelBilling, OpenAISetup, OptOpenAISetup,
+            OptOptOpenAISetup, RetryBackoff, RetryPolicy,
+        },
+        stats::LLMStats,
+        structured::Label,
+        tokens::TokenCounter,
+    };
+}
+
 // General models, note might alias to a specific model
 #[derive(Debug, Clone, Serialize, Deserialize, Display)]
 pub enum OpenAIModel {
@@ -62,6 +121,12 @@ pub enum OpenAIModel {
     GEMINI25PRO,
     #[display("gemini-2.5-flash")]
     GEMINI25FLASH,
+    #[display("claude-opus-4-5")]
+    CLAUDEOPUS45,
+    #[display("claude-sonnet-4-5")]
+    CLAUDESONNET45,
+    #[display("claude-haiku-4-5")]
+    CLAUDEHAIKU45,
     #[display("{_0}")]
     Other(String, PricingInfo),
 }
@@ -69,77 +134,55 @@ pub enum OpenAIModel {
 impl FromStr for OpenAIModel {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "gpt-4o" | "gpt4o" => Ok(Self::GPT4O),
-            "gpt-4" | "gpt" => Ok(Self::GPT4),
-            "gpt-4-turbo" | "gpt4turbo" => Ok(Self::GPT4TURBO),
-            "gpt-4o-mini" | "gpt4omini" => Ok(Self::GPT4OMINI),
-            "o1" => Ok(Self::O1),
-            "o1-mini" => Ok(Self::O1MINI),
-            "gpt-3.5-turbo" | "gpt3.5turbo" => Ok(Self::GPT35TURBO),
-            "gpt-5.2" => Ok(Self::GPT52),
-            "gpt-5-mini" | "gpt-5mini" => Ok(Self::GPT5MINI),
-            "gpt-5" => Ok(Self::GPT5),
-            "gpt-5-nano" | "gpt-5nano" => Ok(Self::GPT5NANO),
-            "gpt-5.1" => Ok(Self::GPT51),
-            "gpt-5-pro" | "gpt5pro" => Ok(Self::GPT5PRO),
-            "gpt-4.1" | "gpt41" => Ok(Self::GPT41),
-            "gpt-4.1-mini" | "gpt41mini" => Ok(Self::GPT41MINI),
-            "gpt-4.1-nano" | "gpt41nano" => Ok(Self::GPT41NANO),
-            "o3" => Ok(Self::O3),
-            "o4-mini" | "o4mini" => Ok(Self::O4MINI),
-            "o3-mini" | "o3mini" => Ok(Self::O3MINI),
-            "o3-pro" | "o3pro" => Ok(Self::O3PRO),
-            "gemini-3-pro-preview" | "gemini-3-pro" => Ok(Self::GEMINI3PRO),
-            "gemini-3-flash-preview" | "gemini-3-flash" => Ok(Self::GEMINI3FLASH),
-            "gemini-2.5-pro" => Ok(Self::GEMINI25PRO),
-            "gemini-2.5-flash" => Ok(Self::GEMINI25FLASH),
-            _ => {
-                if !s.contains(",") {
-                    log::info!("No valid model detected, assume not billed");
-                    return Ok(Self::Other(
-                        s.to_string(),
-                        PricingInfo {
-                            input_tokens: 0.0f64,
-                            output_tokens: 0.0f64,
-                            cached_input_tokens: None,
-                        },
-                    ));
-                }
-                let mut tks = s
-                    .split(",")
-                    .map(|t| t.to_string())
-                    .collect::<VecDeque<String>>();
-
-                if tks.len() >= 2 {
-                    let model = tks.pop_front().unwrap();
-                    let tks = tks
-                        .into_iter()
-                        .map(|t| f64::from_str(&t))
-                        .collect::<Result<Vec<f64>, _>>()
-                        .map_err(|e| e.to_string())?;
+        if let Some(model) = models::known_models()
+            .into_iter()
+            .find(|m| m.to_string() == s || m.aliases().contains(&s))
+        {
+            return Ok(model);
+        }
+        if !s.contains(",") {
+            log::info!("No valid model detected, assume not billed");
+            return Ok(Self::Other(
+                s.to_string(),
+                PricingInfo {
+                    input_tokens: 0.0f64,
+                    output_tokens: 0.0f64,
+                    cached_input_tokens: None,
+                },
+            ));
+        }
+        let mut tks = s
+            .split(",")
+            .map(|t| t.to_string())
+            .collect::<VecDeque<String>>();
 
-                    let pricing = if tks.len() == 2 {
-                        PricingInfo {
-                            input_tokens: tks[0],
-                            output_tokens: tks[1],
-                            cached_input_tokens: None,
-                        }
-                    } else if tks.len() == 3 {
-                        PricingInfo {
-                            input_tokens: tks[0],
-                            output_tokens: tks[1],
-                            cached_input_tokens: Some(tks[2]),
-                        }
-                    } else {
-                        return Err("fail to parse pricing".to_string());
-                    };
+        if tks.len() >= 2 {
+            let model = tks.pop_front().unwrap();
+            let tks = tks
+                .into_iter()
+                .map(|t| f64::from_str(&t))
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|e| e.to_string())?;
 
-                    Ok(Self::Other(model, pricing))
-                } else {
-                    Err("unreconigized model".to_string())
+            let pricing = if tks.len() == 2 {
+                PricingInfo {
+                    input_tokens: tks[0],
+                    output_tokens: tks[1],
+                    cached_input_tokens: None,
                 }
-            }
+            } else if tks.len() == 3 {
+                PricingInfo {
+                    input_tokens: tks[0],
+                    output_tokens: tks[1],
+                    cached_input_tokens: Some(tks[2]),
+                }
+            } else {
+                return Err("fail to parse pricing".to_string());
+            };
+
+            Ok(Self::Other(model, pricing))
+        } else {
+            Err("unreconigized model".to_string())
         }
     }
 }
@@ -193,7 +236,70 @@ pub struct ModelInfo {
 }
 
 impl OpenAIModel {
+    /// Extra names accepted when parsing, besides the canonical name.
+    pub fn aliases(&self) -> &'static [&'static str] {
+        match self {
+            Self::GPT4O => &["gpt4o"],
+            Self::GPT4 => &["gpt"],
+            Self::GPT4TURBO => &["gpt4turbo"],
+            Self::GPT4OMINI => &["gpt4omini"],
+            Self::GPT35TURBO => &["gpt3.5turbo"],
+            Self::GPT5MINI => &["gpt-5mini"],
+            Self::GPT5NANO => &["gpt-5nano"],
+            Self::GPT5PRO => &["gpt5pro"],
+            Self::GPT41 => &["gpt41"],
+            Self::GPT41MINI => &["gpt41mini"],
+            Self::GPT41NANO => &["gpt41nano"],
+            Self::O4MINI => &["o4mini"],
+            Self::O3MINI => &["o3mini"],
+            Self::O3PRO => &["o3pro"],
+            Self::GEMINI3PRO => &["gemini-3-pro"],
+            Self::GEMINI3FLASH => &["gemini-3-flash"],
+            Self::CLAUDEOPUS45 => &["claude-opus-4-5-20251101"],
+            Self::CLAUDESONNET45 => &["claude-sonnet-4-5-20250929"],
+            Self::CLAUDEHAIKU45 => &["claude-haiku-4-5-20251001"],
+            _ => &[],
+        }
+    }
+
+    /// Whether the model takes temperature and penalties, reasoning models only
+    /// accept their defaults.
+    pub fn accepts_sampling(&self) -> bool {
+        match self {
+            Self::O1
+            | Self::O1MINI
+            | Self::O3
+            | Self::O3MINI
+            | Self::O3PRO
+            | Self::O4MINI
+            | Self::GPT5
+            | Self::GPT5MINI
+            | Self::GPT5NANO
+            | Self::GPT5PRO
+            | Self::GPT51
+            | Self::GPT52 => false,
+            Self::Other(name, _) => !["o1", "o3", "o4", "gpt-5"]
+                .iter()
+                .any(|family| name.starts_with(family)),
+            _ => true,
+        }
+    }
+
+    /// Pricing synced from a registry (see [`pricing::sync_pricing`]) if any,
+    /// otherwise the built-in table. Explicit pricing on `Other` always wins.
     pub fn pricing(&self) -> PricingInfo {
+        match self {
+            Self::Other(_, pricing)
+                if pricing.input_tokens != 0.0 || pricing.output_tokens != 0.0 =>
+            {
+                *pricing
+            }
+            _ => pricing::pricing_override(&self.to_string())
+                .unwrap_or_else(|| self.builtin_pricing()),
+        }
+    }
+
+    pub fn builtin_pricing(&self) -> PricingInfo {
         match self {
             Self::GPT4O => PricingInfo {
                 input_tokens: 2.5,
@@ -315,6 +421,22 @@ impl OpenAIModel {
                 output_tokens: 2.50,
                 cached_input_tokens: None,
             },
+            // Cache writes are billed as plain input.
+            Self::CLAUDEOPUS45 => PricingInfo {
+                input_tokens: 5.00,
+                output_tokens: 25.00,
+                cached_input_tokens: Some(0.50),
+            },
+            Self::CLAUDESONNET45 => PricingInfo {
+                input_tokens: 3.00,   // TODO: 6.00 for > 200k tokens
+                output_tokens: 15.00, // TODO: 22.50 for > 200k tokens
+                cached_input_tokens: Some(0.30),
+            },
+            Self::CLAUDEHAIKU45 => PricingInfo {
+                input_tokens: 1.00,
+                output_tokens: 5.00,
+                cached_input_tokens: Some(0.10),
+            },
             Self::Other(_, pricing) => *pricing,
         }
     }
@@ -361,6 +483,21 @@ impl OpenAIModel {
                 output_tokens: 2.20,
                 cached_input_tokens: Some(0.275),
             }),
+            Self::CLAUDEOPUS45 => Some(PricingInfo {
+                input_tokens: 2.50,
+                output_tokens: 12.50,
+                cached_input_tokens: None,
+            }),
+            Self::CLAUDESONNET45 => Some(PricingInfo {
+                input_tokens: 1.50,
+                output_tokens: 7.50,
+                cached_input_tokens: None,
+            }),
+            Self::CLAUDEHAIKU45 => Some(PricingInfo {
+                input_tokens: 0.50,
+                output_tokens: 2.50,
+                cached_input_tokens: None,
+            }),
             _ => None,
         }
     }
@@ -468,7 +605,31 @@ impl OpenAIModel {
                 max_output_tokens: 4_096,
                 knowledge_cutoff: NaiveDate::from_ymd_opt(2021, 9, 1).unwrap(),
             }),
+            Self::CLAUDEOPUS45 => Some(ModelInfo {
+                context_window: 200_000,
+                max_output_tokens: 64_000,
+                knowledge_cutoff: NaiveDate::from_ymd_opt(2025, 5, 31).unwrap(),
+            }),
+            Self::CLAUDESONNET45 => Some(ModelInfo {
+                context_window: 200_000,
+                max_output_tokens: 64_000,
+                knowledge_cutoff: NaiveDate::from_ymd_opt(2025, 1, 31).unwrap(),
+            }),
+            Self::CLAUDEHAIKU45 => Some(ModelInfo {
+                context_window: 200_000,
+                max_output_tokens: 64_000,
+                knowledge_cutoff: NaiveDate::from_ymd_opt(2025, 2, 28).unwrap(),
+            }),
             _ => None,
         }
     }
+
+    /// Context window in tokens, None for unknown models.
+    pub fn context_window(&self) -> Option<u64> {
+        self.info().map(|info| info.context_window)
+    }
+
+    pub fn max_output_tokens(&self) -> Option<u64> {
+        self.info().map(|info| info.max_output_tokens)
+    }
 }
@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     fmt::{Debug, Display},
     ops::{Deref, DerefMut},
     path::{Path, PathBuf},
@@ -28,21 +29,45 @@ use async_openai::{
         ChatCompletionToolChoiceOption, ChatCompletionTools, CompletionUsage,
         CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
         CreateChatCompletionStreamResponse, CustomName, FinishReason, FunctionCall,
-        ReasoningEffort, Role, ToolChoiceOptions,
+        ReasoningEffort, Role, ServiceTier, ToolChoiceOptions,
     },
 };
+use chrono::{DateTime, Datelike, Timelike, Utc};
 use clap::Args;
 use color_eyre::{
     Result,
     eyre::{OptionExt, eyre},
 };
-use futures_util::StreamExt;
+use derive_more::derive::Display;
+use futures_util::{StreamExt, stream, stream::BoxStream};
 use itertools::Itertools;
 use log::{debug, info, trace, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
-use tokio::{io::AsyncWriteExt, sync::RwLock};
-
-use crate::{OpenAIModel, error::PromptError};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{Mutex, RwLock, RwLockWriteGuard},
+};
+use tracing::{Span, field::Empty};
+
+use crate::{
+    OpenAIModel,
+    anthropic::{ANTHROPIC_API_BASE, AnthropicClient, AnthropicConfig},
+    backend::ChatBackend,
+    cassette::{CassetteClient, CassetteMode},
+    embeddings::EmbeddingModel,
+    error::PromptError,
+    genai::GenAIRecord,
+    memory::{DEFAULT_MEMORY_FILES, inject_memory, load_memory},
+    pricing::{PricingSync, sync_pricing},
+    provider::{Provider, RoleMapping},
+    ratelimit::{RateLimitInfo, SpendLimiter},
+    redact::{DeltaRestorer, RedactionMap, Redactor},
+    replay::ReplayClient,
+    stats::{DumpStats, LLMStats},
+    tokens::{TokenCounter, default_counter},
+};
 
 #[derive(Clone, Debug, Default)]
 struct ToolCallAcc {
@@ -51,6 +76,171 @@ struct ToolCallAcc {
     arguments: String,
 }
 
+// Chunks as the provider sent them, with the mapping to restore a redacted request.
+pub(crate) type ChunkStream<'a> = (
+    BoxStream<'a, Result<CreateChatCompletionStreamResponse, PromptError>>,
+    Option<RedactionMap>,
+);
+
+// Assembles streamed chunks back into a full response.
+#[derive(Debug, Default)]
+pub(crate) struct StreamAcc {
+    id: Option<String>,
+    created: Option<u32>,
+    model: Option<String>,
+    service_tier: Option<ServiceTier>,
+    system_fingerprint: Option<String>,
+    usage: Option<CompletionUsage>,
+    contents: Vec<String>,
+    finish_reasons: Vec<Option<FinishReason>>,
+    tool_calls: Vec<Vec<ToolCallAcc>>,
+}
+
+impl StreamAcc {
+    #[allow(deprecated)]
+    pub(crate) fn push(&mut self, chunk: CreateChatCompletionStreamResponse) {
+        if self.id.is_none() {
+            self.id = Some(chunk.id.clone());
+        }
+        self.created = Some(chunk.created);
+        self.model = Some(chunk.model.clone());
+        self.service_tier = chunk.service_tier.clone();
+        self.system_fingerprint = chunk.system_fingerprint.clone();
+        if let Some(u) = chunk.usage.clone() {
+            self.usage = Some(u);
+        }
+
+        for ch in chunk.choices.into_iter() {
+            let idx = ch.index as usize;
+            if self.contents.len() <= idx {
+                self.contents.resize_with(idx + 1, String::new);
+                self.finish_reasons.resize_with(idx + 1, || None);
+                self.tool_calls.resize_with(idx + 1, Vec::new);
+            }
+            if let Some(delta) = ch.delta.content {
+                self.contents[idx].push_str(&delta);
+            }
+            if let Some(tcs) = ch.delta.tool_calls {
+                for tc in tcs.into_iter() {
+                    let tc_idx = tc.index as usize;
+                    if self.tool_calls[idx].len() <= tc_idx {
+                        self.tool_calls[idx].resize_with(tc_idx + 1, ToolCallAcc::default);
+                    }
+                    let acc = &mut self.tool_calls[idx][tc_idx];
+                    if let Some(id) = tc.id {
+                        acc.id = id;
+                    }
+                    if let Some(func) = tc.function {
+                        if let Some(name) = func.name {
+                            acc.name = name;
+                        }
+                        if let Some(args) = func.arguments {
+                            acc.arguments.push_str(&args);
+                        }
+                    }
+                }
+            }
+            if ch.finish_reason.is_some() {
+                self.finish_reasons[idx] = ch.finish_reason;
+            }
+        }
+    }
+
+    /// Choices without a finish reason are marked [`FinishReason::Length`] if `truncated`.
+    #[allow(deprecated)]
+    pub(crate) fn finish(self, truncated: bool, model: &str) -> CreateChatCompletionResponse {
+        let mut choices = Vec::new();
+        for (idx, content) in self.contents.into_iter().enumerate() {
+            let finish_reason = match self.finish_reasons.get(idx).cloned().unwrap_or(None) {
+                None if truncated => Some(FinishReason::Length),
+                reason => reason,
+            };
+            let built_tool_calls = self
+                .tool_calls
+                .get(idx)
+                .cloned()
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|t| !t.name.trim().is_empty() || !t.arguments.trim().is_empty())
+                .map(|t| {
+                    ChatCompletionMessageToolCalls::Function(ChatCompletionMessageToolCall {
+                        id: if t.id.trim().is_empty() {
+                            format!("toolcall-{}", idx)
+                        } else {
+                            t.id
+                        },
+                        function: FunctionCall {
+                            name: t.name,
+                            arguments: t.arguments,
+                        },
+                    })
+                })
+                .collect::<Vec<_>>();
+            let tool_calls_opt = if built_tool_calls.is_empty() {
+                None
+            } else {
+                Some(built_tool_calls)
+            };
+            choices.push(ChatChoice {
+                index: idx as u32,
+                message: ChatCompletionResponseMessage {
+                    content: if content.is_empty() {
+                        None
+                    } else {
+                        Some(content)
+                    },
+                    refusal: None,
+                    tool_calls: tool_calls_opt,
+                    annotations: None,
+                    role: Role::Assistant,
+                    function_call: None,
+                    audio: None,
+                },
+                finish_reason,
+                logprobs: None,
+            });
+        }
+        if choices.is_empty() {
+            choices.push(ChatChoice {
+                index: 0,
+                message: ChatCompletionResponseMessage {
+                    content: Some(String::new()),
+                    refusal: None,
+                    tool_calls: None,
+                    annotations: None,
+                    role: Role::Assistant,
+                    function_call: None,
+                    audio: None,
+                },
+                finish_reason: None,
+                logprobs: None,
+            });
+        }
+
+        CreateChatCompletionResponse {
+            id: self.id.unwrap_or_else(|| "stream".to_string()),
+            choices,
+            created: self.created.unwrap_or(0),
+            model: self.model.unwrap_or_else(|| model.to_string()),
+            service_tier: self.service_tier,
+            system_fingerprint: self.system_fingerprint,
+            object: "chat.completion".to_string(),
+            usage: self.usage,
+        }
+    }
+}
+
+// What `complete_stream` needs to dump and bill the stream once it ends.
+#[derive(Debug)]
+struct StreamFinish {
+    prefix: String,
+    tag: Option<String>,
+    tool_tokens: usize,
+    estimated_input: usize,
+    model: String,
+    debug_fp: Option<(u64, PathBuf)>,
+}
+
 // Upstream implementation is flawed
 #[derive(Debug, Clone)]
 pub struct LLMToolChoice(pub ChatCompletionToolChoiceOption);
@@ -133,6 +323,14 @@ macro_rules! make_openai_args {
             )]
             pub openai_url: String,
 
+            #[arg(
+                long,
+                alias = "openai-flavor",
+                env = concat!($prefix, "OPENAI_PROVIDER"),
+                default_value = "openai"
+            )]
+            pub provider: Provider,
+
             #[arg(long, env = concat!($prefix, "AZURE_OPENAI_ENDPOINT"))]
             pub azure_openai_endpoint: Option<String>,
 
@@ -148,27 +346,125 @@ macro_rules! make_openai_args {
             #[arg(long, default_value_t = 10.0, env = concat!($prefix,"OPENAI_BILLING_CAP"))]
             pub biling_cap: f64,
 
+            #[arg(long, env = concat!($prefix,"OPENAI_BILLING_HOURLY_CAP"))]
+            pub billing_hourly_cap: Option<f64>,
+
+            #[arg(long, env = concat!($prefix,"OPENAI_BILLING_DAILY_CAP"))]
+            pub billing_daily_cap: Option<f64>,
+
+            #[arg(long, env = concat!($prefix,"OPENAI_BILLING_MONTHLY_CAP"))]
+            pub billing_monthly_cap: Option<f64>,
+
+            #[arg(long, env = concat!($prefix,"OPENAI_BILLING_STATE"))]
+            pub openai_billing_state: Option<PathBuf>,
+
+            /// Append every billed request to this JSONL file.
+            #[arg(long, env = concat!($prefix,"OPENAI_BILLING_LEDGER"))]
+            pub openai_billing_ledger: Option<PathBuf>,
+
+            /// Throttle completions to this USD per hour on average.
+            #[arg(long, env = concat!($prefix,"OPENAI_BILLING_SPEND_RATE"))]
+            pub billing_spend_rate: Option<f64>,
+
+            /// USD that may be spent at once under --billing-spend-rate, defaults to
+            /// ten minutes of spend.
+            #[arg(long, env = concat!($prefix,"OPENAI_BILLING_SPEND_BURST"))]
+            pub billing_spend_burst: Option<f64>,
+
+            #[arg(long, env = concat!($prefix,"OPENAI_PRICING_URL"))]
+            pub pricing_url: Option<String>,
+
+            #[arg(long, env = concat!($prefix,"OPENAI_PRICING_SHA256"))]
+            pub pricing_sha256: Option<String>,
+
             #[arg(long, env = concat!($prefix,"OPENAI_API_MODEL"), default_value = "o1")]
             pub model: OpenAIModel,
 
+            #[arg(long, env = concat!($prefix,"OPENAI_EMBEDDING_MODEL"), default_value = "text-embedding-3-small")]
+            pub embedding_model: EmbeddingModel,
+
+            #[arg(long, env = concat!($prefix, "AZURE_EMBEDDING_DEPLOYMENT"))]
+            pub azure_embedding_deployment: Option<String>,
+
             #[arg(long, env = concat!($prefix,"LLM_DEBUG"))]
             pub llm_debug: Option<PathBuf>,
 
-            #[arg(long, env = concat!($prefix, "LLM_TEMPERATURE"), default_value_t = 0.8)]
-            pub llm_temperature: f32,
+            #[arg(long, env = concat!($prefix, "LLM_GENAI_LOG"))]
+            pub llm_genai_log: Option<PathBuf>,
+
+            /// Serve the responses recorded in this --llm-debug directory instead of
+            /// calling the provider.
+            #[arg(long, env = concat!($prefix, "LLM_REPLAY"))]
+            pub llm_replay: Option<PathBuf>,
+
+            /// Record all interactions to this json file, or replay them from it.
+            #[arg(long, env = concat!($prefix, "LLM_CASSETTE"))]
+            pub llm_cassette: Option<PathBuf>,
+
+            /// record, exact, messages, sequential or auto (replay by messages if the
+            /// cassette exists, otherwise record).
+            #[arg(long, env = concat!($prefix, "LLM_CASSETTE_MODE"), default_value = "auto")]
+            pub llm_cassette_mode: CassetteMode,
+
+            #[arg(long, env = concat!($prefix, "LLM_DEBUG_CONTROL_CHARS"), default_value = "keep")]
+            pub llm_debug_control_chars: ControlChars,
 
-            #[arg(long, env = concat!($prefix, "LLM_PRESENCE_PENALTY"), default_value_t = 0.0)]
-            pub llm_presence_penalty: f32,
+            #[arg(long, env = concat!($prefix, "LLM_DEBUG_MAX_MESSAGE_CHARS"))]
+            pub llm_debug_max_message_chars: Option<usize>,
+
+            #[arg(long, env = concat!($prefix, "LLM_TEMPERATURE"))]
+            pub llm_temperature: Option<f32>,
+
+            #[arg(long, env = concat!($prefix, "LLM_PRESENCE_PENALTY"))]
+            pub llm_presence_penalty: Option<f32>,
 
             #[arg(long, env = concat!($prefix, "LLM_PROMPT_TIMEOUT"), default_value_t = 120)]
             pub llm_prompt_timeout: u64,
 
+            /// Seconds for each HTTP exchange with the provider, unlike
+            /// --llm-prompt-timeout a timed out exchange is retried as a failure.
+            #[arg(long, env = concat!($prefix, "LLM_HTTP_TIMEOUT"))]
+            pub llm_http_timeout: Option<u64>,
+
             #[arg(long, env = concat!($prefix, "LLM_RETRY"), default_value_t = 5)]
             pub llm_retry: u64,
 
+            #[arg(long, env = concat!($prefix, "LLM_RETRY_BASE_MS"), default_value_t = 1000)]
+            pub llm_retry_base_ms: u64,
+
+            #[arg(long, env = concat!($prefix, "LLM_RETRY_MAX_MS"), default_value_t = 30000)]
+            pub llm_retry_max_ms: u64,
+
+            #[arg(long, env = concat!($prefix, "LLM_RETRY_DEADLINE"), default_value_t = 0)]
+            pub llm_retry_deadline: u64,
+
+            #[arg(long, env = concat!($prefix, "LLM_RETRY_COST"))]
+            pub llm_retry_cost: Option<f64>,
+
             #[arg(long, env = concat!($prefix, "LLM_MAX_COMPLETION_TOKENS"), default_value_t = 16384)]
             pub llm_max_completion_tokens: u32,
 
+            /// Size max_completion_tokens to what is left of the context window after
+            /// the prompt, capped by the model output limit.
+            #[arg(
+                long,
+                env = concat!($prefix, "LLM_AUTO_MAX_TOKENS"),
+                default_value_t = false,
+                value_parser = clap::builder::BoolishValueParser::new()
+            )]
+            pub llm_auto_max_tokens: bool,
+
+            /// Tokens kept free with --llm-auto-max-tokens for counting errors.
+            #[arg(long, env = concat!($prefix, "LLM_AUTO_MAX_TOKENS_MARGIN"), default_value_t = 1024)]
+            pub llm_auto_max_tokens_margin: u32,
+
+            /// Override the context window of the model, e.g. for a custom model.
+            #[arg(long, env = concat!($prefix, "LLM_CONTEXT_WINDOW"))]
+            pub llm_context_window: Option<u64>,
+
+            #[arg(long, env = concat!($prefix, "LLM_MAX_OUTPUT_TOKENS"))]
+            pub llm_max_output_tokens: Option<u64>,
+
             #[arg(long, env = concat!($prefix, "LLM_TOOL_CHOINCE"))]
             pub llm_tool_choice: Option<LLMToolChoice>,
 
@@ -180,11 +476,57 @@ macro_rules! make_openai_args {
             )]
             pub llm_stream: bool,
 
+            #[arg(
+                long,
+                env = concat!($prefix, "LLM_SALVAGE_PARTIAL"),
+                default_value_t = false,
+                value_parser = clap::builder::BoolishValueParser::new()
+            )]
+            pub llm_salvage_partial: bool,
+
             #[arg(
                 long,
                 env = concat!($prefix, "LLM_REASONING_EFFORT"),
             )]
-            pub reasoning_effort: Option<Reasoning>
+            pub reasoning_effort: Option<Reasoning>,
+
+            #[arg(long, env = concat!($prefix, "LLM_USAGE_FALLBACK"), default_value = "zero")]
+            pub llm_usage_fallback: UsageFallback,
+
+            #[arg(long, env = concat!($prefix, "LLM_LENGTH_WARN_RATIO"), default_value_t = 0.1)]
+            pub llm_length_warn_ratio: f64,
+
+            #[arg(long, env = concat!($prefix, "LLM_MEMORY_ROOT"))]
+            pub llm_memory_root: Option<PathBuf>,
+
+            #[arg(
+                long,
+                env = concat!($prefix, "LLM_MEMORY_FILES"),
+                value_delimiter = ',',
+                default_values_t = DEFAULT_MEMORY_FILES.iter().map(|s| s.to_string())
+            )]
+            pub llm_memory_files: Vec<String>,
+
+            /// keep, system or user, the provider default if unset.
+            #[arg(long, env = concat!($prefix, "LLM_ROLE_MAPPING"))]
+            pub llm_role_mapping: Option<RoleMapping>,
+
+            #[arg(long, env = concat!($prefix, "LLM_IDEMPOTENCY_HEADER"))]
+            pub llm_idempotency_header: Option<HeaderName>,
+
+            #[arg(long, env = concat!($prefix, "LLM_HEDGE_AFTER_MS"))]
+            pub llm_hedge_after_ms: Option<u64>,
+
+            #[arg(long, env = concat!($prefix, "LLM_HEDGE_URL"))]
+            pub llm_hedge_url: Option<String>,
+
+            #[arg(
+                long,
+                env = concat!($prefix, "LLM_REDACT_PII"),
+                default_value_t = false,
+                value_parser = clap::builder::BoolishValueParser::new()
+            )]
+            pub llm_redact_pii: bool,
         }
 
         impl $struct_name {
@@ -193,11 +535,32 @@ macro_rules! make_openai_args {
                     llm_temperature: self.llm_temperature,
                     llm_presence_penalty: self.llm_presence_penalty,
                     llm_prompt_timeout: self.llm_prompt_timeout,
+                    llm_http_timeout: self.llm_http_timeout,
                     llm_retry: self.llm_retry,
                     llm_max_completion_tokens: self.llm_max_completion_tokens,
+                    llm_auto_max_tokens: self.llm_auto_max_tokens,
+                    llm_auto_max_tokens_margin: self.llm_auto_max_tokens_margin,
+                    llm_context_window: self.llm_context_window,
+                    llm_max_output_tokens: self.llm_max_output_tokens,
                     llm_tool_choice: self.llm_tool_choice.clone(),
                     llm_stream: self.llm_stream,
-                    reasoning_effort: self.reasoning_effort.clone()
+                    llm_salvage_partial: self.llm_salvage_partial,
+                    reasoning_effort: self.reasoning_effort.clone(),
+                    llm_retry_cost: self.llm_retry_cost,
+                    llm_retry_base_ms: self.llm_retry_base_ms,
+                    llm_retry_max_ms: self.llm_retry_max_ms,
+                    llm_retry_deadline: self.llm_retry_deadline,
+                }
+            }
+
+            /// Merge the pricing registry at `pricing_url` if given, call before `to_llm`.
+            pub async fn sync_pricing(&self) -> Result<usize, PromptError> {
+                if let Some(url) = self.pricing_url.as_ref() {
+                    let mut sync = PricingSync::new(url);
+                    sync.sha256 = self.pricing_sha256.clone();
+                    sync_pricing(&sync).await
+                } else {
+                    Ok(0)
                 }
             }
 
@@ -213,6 +576,16 @@ macro_rules! make_openai_args {
                         )
                         .with_api_version(&self.azure_api_version);
                     SupportedConfig::Azure(cfg)
+                } else if self.provider == Provider::Anthropic {
+                    let api_base = if self.openai_url == "https://api.openai.com/v1" {
+                        ANTHROPIC_API_BASE
+                    } else {
+                        &self.openai_url
+                    };
+                    let cfg = AnthropicConfig::default()
+                        .with_api_base(api_base)
+                        .with_api_key(self.openai_key.clone().unwrap_or_default());
+                    SupportedConfig::Anthropic(cfg)
                 } else {
                     let cfg = OpenAIConfig::new()
                         .with_api_base(&self.openai_url)
@@ -222,42 +595,122 @@ macro_rules! make_openai_args {
             }
 
             pub fn to_llm(&self) -> LLM {
-                let billing = RwLock::new(ModelBilling::new(self.biling_cap));
-
-                let debug_path = if let Some(dbg) = self.llm_debug.as_ref() {
-                    let pid = std::process::id();
+                let client = match self.llm_replay.as_ref() {
+                    Some(dir) => LLMClient::Replay(
+                        ReplayClient::load(dir).expect("Fail to load llm replay?"),
+                    ),
+                    None => LLMClient::new(self.to_config()),
+                };
+                self.to_llm_with(client)
+            }
 
-                    let mut cnt = 0u64;
-                    let debug_path;
-                    loop {
-                        let prefix = if $prefix.len() == 0 {
-                            "main".to_string()
-                        } else {
-                            $prefix.to_lowercase()
-                        };
-                        let test_path = dbg.join(format!("{}-{}-{}", pid, cnt, prefix));
-                        if !test_path.exists() {
-                            std::fs::create_dir_all(&test_path).expect("Fail to create llm debug path?");
-                            debug_path = Some(test_path);
-                            debug!("The path to save LLM interactions is {:?}", &debug_path);
-                            break;
-                        } else {
-                            cnt += 1;
-                        }
+            /// Like `to_llm` but completions go to `client`, e.g.
+            /// [`LLMClient::Custom`] wrapping your own [`ChatBackend`].
+            pub fn to_llm_with(&self, client: LLMClient) -> LLM {
+                let mut billing = ModelBilling::new(self.biling_cap);
+                for (period, cap) in [
+                    (QuotaPeriod::Hour, self.billing_hourly_cap),
+                    (QuotaPeriod::Day, self.billing_daily_cap),
+                    (QuotaPeriod::Month, self.billing_monthly_cap),
+                ] {
+                    if let Some(cap) = cap {
+                        billing = billing.with_window(period, cap);
+                    }
+                }
+                if let Some(path) = self.openai_billing_state.as_ref()
+                    && path.exists()
+                {
+                    match ModelBilling::load(path) {
+                        Ok(saved) => billing.restore(saved),
+                        Err(e) => warn!("Fail to load billing state due to {}", e),
                     }
+                }
+                let billing = RwLock::new(billing);
+
+                let debug_path = self.llm_debug.as_ref().map(|dbg| {
+                    let prefix = if $prefix.len() == 0 {
+                        "main".to_string()
+                    } else {
+                        $prefix.to_lowercase()
+                    };
+                    let debug_path = create_debug_session(dbg, &prefix, &self.model)
+                        .expect("Fail to create llm debug path?");
+                    debug!("The path to save LLM interactions is {:?}", &debug_path);
                     debug_path
-                } else {
-                    None
+                });
+
+                let client = match self.llm_cassette.as_ref() {
+                    Some(path) => LLMClient::Cassette(
+                        CassetteClient::new(client, path, self.llm_cassette_mode)
+                            .expect("Fail to load llm cassette?"),
+                    ),
+                    None => client,
                 };
 
                 LLM {
                     llm: Arc::new(LLMInner {
-                        client: LLMClient::new(self.to_config()),
+                        client,
                         model: self.model.clone(),
                         billing,
                         llm_debug: debug_path,
                         llm_debug_index: AtomicU64::new(0),
                         default_settings: self.settings(),
+                        provider: self.provider,
+                        memory: self.llm_memory_root.as_ref().and_then(|root| {
+                            load_memory(root, &self.llm_memory_files)
+                                .inspect_err(|e| warn!("Fail to load memory file due to {}", e))
+                                .ok()
+                                .flatten()
+                        }),
+                        stats: RwLock::new(LLMStats::default()),
+                        length_warn_ratio: self.llm_length_warn_ratio,
+                        idempotency_header: self.llm_idempotency_header.clone(),
+                        token_counter: default_counter(&self.model),
+                        usage_fallback: if self.provider.quirks().no_usage
+                            && self.llm_usage_fallback == UsageFallback::Zero
+                        {
+                            UsageFallback::Estimate
+                        } else {
+                            self.llm_usage_fallback
+                        },
+                        genai_log: self.llm_genai_log.clone(),
+                        billing_state: self.openai_billing_state.clone(),
+                        billing_save: Mutex::new(()),
+                        billing_ledger: self.openai_billing_ledger.clone(),
+                        billing_hooks: BillingHooks::default(),
+                        role_mapping: self
+                            .llm_role_mapping
+                            .unwrap_or(self.provider.quirks().role_mapping),
+                        embedding_model: self.embedding_model.clone(),
+                        rate_limit: RwLock::new(RateLimitInfo::default()),
+                        spend_limiter: self.billing_spend_rate.map(|rate| {
+                            SpendLimiter::new(rate, self.billing_spend_burst.unwrap_or(rate / 6.0))
+                        }),
+                        embedding_client: self.azure_embedding_deployment.as_ref().and_then(|dep| {
+                            let SupportedConfig::Azure(cfg) = self.to_config() else {
+                                return None;
+                            };
+                            Some(LLMClient::new(SupportedConfig::Azure(cfg.with_deployment_id(dep))))
+                        }),
+                        dump_options: DumpOptions {
+                            control_chars: self.llm_debug_control_chars,
+                            max_message_chars: self.llm_debug_max_message_chars,
+                        },
+                        hedge: self.llm_hedge_after_ms.map(|ms| HedgeSettings {
+                            after: Duration::from_millis(ms),
+                            client: self.llm_hedge_url.as_ref().map(|url| {
+                                LLMClient::new(SupportedConfig::OpenAI(
+                                    OpenAIConfig::new()
+                                        .with_api_base(url)
+                                        .with_api_key(self.openai_key.clone().unwrap_or_default()),
+                                ))
+                            }),
+                        }),
+                        redactor: if self.llm_redact_pii {
+                            Some(Redactor::default())
+                        } else {
+                            None
+                        },
                     }),
                 }
             }
@@ -271,26 +724,124 @@ make_openai_args!(OptOptOpenAISetup, "OPT_OPT_");
 
 #[derive(Args, Clone, Debug)]
 pub struct LLMSettings {
-    pub llm_temperature: f32,
-    pub llm_presence_penalty: f32,
+    /// Unset means the provider default.
+    pub llm_temperature: Option<f32>,
+    pub llm_presence_penalty: Option<f32>,
     pub llm_prompt_timeout: u64,
+    /// Seconds for each HTTP exchange, see [`LLMInner::with_http_timeout`].
+    pub llm_http_timeout: Option<u64>,
     pub llm_retry: u64,
     pub llm_max_completion_tokens: u32,
+    /// Replace `llm_max_completion_tokens` with the context left after the prompt
+    /// minus `llm_auto_max_tokens_margin`, for models with a known context window.
+    pub llm_auto_max_tokens: bool,
+    pub llm_auto_max_tokens_margin: u32,
+    /// Override the limits of the model, e.g. for an `Other` model served elsewhere.
+    pub llm_context_window: Option<u64>,
+    pub llm_max_output_tokens: Option<u64>,
     pub llm_tool_choice: Option<LLMToolChoice>,
     pub llm_stream: bool,
+    /// After a timeout, retry with streaming and return whatever arrived before the
+    /// next timeout, marked with [`FinishReason::Length`].
+    pub llm_salvage_partial: bool,
     pub reasoning_effort: Option<Reasoning>,
+    pub llm_retry_cost: Option<f64>,
+    /// First retry delay, doubled on each retry up to `llm_retry_max_ms`.
+    pub llm_retry_base_ms: u64,
+    pub llm_retry_max_ms: u64,
+    /// Overall deadline over all attempts in seconds, 0 means no deadline.
+    pub llm_retry_deadline: u64,
+}
+
+/// Delay between retries, with jitter.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryBackoff {
+    pub base: Duration,
+    pub max: Duration,
+    /// Deadline over all attempts, independent of the per-attempt timeout.
+    pub deadline: Option<Duration>,
+}
+
+impl RetryBackoff {
+    /// Delay before the `retry`-th retry (1-based): a random duration between half and
+    /// all of the capped exponential delay.
+    pub fn delay(&self, retry: u64) -> Duration {
+        let exp = self
+            .base
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1).min(31) as u32))
+            .min(self.max);
+        exp / 2 + exp.mul_f64(fastrand::f64() / 2.0)
+    }
+}
+
+/// How many times a failed or timed out completion is retried.
+#[derive(Debug, Clone, Copy)]
+pub enum RetryPolicy {
+    Attempts(u64),
+    /// Retry while the extra dollars spent on retries stay below `max_extra_usd`.
+    Cost {
+        max_extra_usd: f64,
+        max_attempts: u64,
+    },
+}
+
+impl LLMSettings {
+    pub fn retry_policy(&self) -> RetryPolicy {
+        match self.llm_retry_cost {
+            Some(max_extra_usd) => RetryPolicy::Cost {
+                max_extra_usd,
+                max_attempts: self.llm_retry,
+            },
+            None => RetryPolicy::Attempts(self.llm_retry),
+        }
+    }
+
+    pub fn backoff(&self) -> RetryBackoff {
+        RetryBackoff {
+            base: Duration::from_millis(self.llm_retry_base_ms),
+            max: Duration::from_millis(self.llm_retry_max_ms),
+            deadline: if self.llm_retry_deadline == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(self.llm_retry_deadline))
+            },
+        }
+    }
+
+    pub fn prompt_timeout(&self) -> Duration {
+        if self.llm_prompt_timeout == 0 {
+            Duration::MAX
+        } else {
+            Duration::from_secs(self.llm_prompt_timeout)
+        }
+    }
+}
+
+/// Hedged requests: fire the same request again after `after` without a response,
+/// to `client` if given or the primary client otherwise. The losing request is billed
+/// its estimated prompt only, so caps may slightly under-report with hedging.
+#[derive(Debug, Clone)]
+pub struct HedgeSettings {
+    pub after: Duration,
+    pub client: Option<LLMClient>,
 }
 
 #[derive(Debug, Clone)]
 pub enum SupportedConfig {
     Azure(AzureConfig),
     OpenAI(OpenAIConfig),
+    Anthropic(AnthropicConfig),
 }
 
 #[derive(Debug, Clone)]
 pub enum LLMClient {
     Azure(Client<AzureConfig>),
     OpenAI(Client<OpenAIConfig>),
+    Anthropic(AnthropicClient),
+    Replay(ReplayClient),
+    Cassette(CassetteClient),
+    /// Any other [`ChatBackend`], e.g. a mock or a third-party provider.
+    Custom(Arc<dyn ChatBackend>),
 }
 
 impl LLMClient {
@@ -298,6 +849,18 @@ impl LLMClient {
         match config {
             SupportedConfig::Azure(cfg) => Self::Azure(Client::with_config(cfg)),
             SupportedConfig::OpenAI(cfg) => Self::OpenAI(Client::with_config(cfg)),
+            SupportedConfig::Anthropic(cfg) => Self::Anthropic(AnthropicClient::new(cfg)),
+        }
+    }
+
+    pub fn backend(&self) -> &dyn ChatBackend {
+        match self {
+            Self::Azure(cl) => cl,
+            Self::OpenAI(cl) => cl,
+            Self::Anthropic(cl) => cl,
+            Self::Replay(cl) => cl,
+            Self::Cassette(cl) => cl,
+            Self::Custom(cl) => cl.as_ref(),
         }
     }
 
@@ -305,55 +868,436 @@ impl LLMClient {
         &self,
         req: CreateChatCompletionRequest,
     ) -> Result<CreateChatCompletionResponse, OpenAIError> {
-        match self {
-            Self::Azure(cl) => cl.chat().create(req).await,
-            Self::OpenAI(cl) => cl.chat().create(req).await,
-        }
+        self.create_chat_with_headers(req, HeaderMap::new())
+            .await
+            .map_err(PromptError::into_openai)
+    }
+
+    /// Errors carry what the provider answered, see [`PromptError::Provider`].
+    pub async fn create_chat_with_headers(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        self.backend().create_chat(req, headers).await
+    }
+
+    pub async fn create_chat_stream_with_headers(
+        &self,
+        req: CreateChatCompletionRequest,
+        headers: HeaderMap,
+    ) -> Result<ChatCompletionResponseStream, PromptError> {
+        self.backend().create_chat_stream(req, headers).await
     }
 
     pub async fn create_chat_stream(
         &self,
         req: CreateChatCompletionRequest,
     ) -> Result<ChatCompletionResponseStream, OpenAIError> {
-        match self {
-            Self::Azure(cl) => cl.chat().create_stream(req).await,
-            Self::OpenAI(cl) => cl.chat().create_stream(req).await,
+        self.create_chat_stream_with_headers(req, HeaderMap::new())
+            .await
+            .map_err(PromptError::into_openai)
+    }
+}
+
+// Per attempt transport options, not part of the request body.
+#[derive(Debug, Clone, Default)]
+struct SendOptions {
+    deadline: Option<tokio::time::Instant>,
+    headers: HeaderMap,
+}
+
+fn new_idempotency_key() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seed = format!(
+        "{}-{}-{}",
+        std::process::id(),
+        Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    Sha256::digest(seed.as_bytes())
+        .iter()
+        .take(16)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Random 16 hex digits naming a debug session directory.
+fn new_session_id() -> String {
+    format!("{:016x}", fastrand::u64(..))
+}
+
+tokio::task_local! {
+    static BILLING_TAG: String;
+    static HTTP_TIMEOUT: Duration;
+}
+
+/// Tag set by [`LLMInner::with_billing_tag`] around the current task, if any.
+pub(crate) fn billing_tag() -> Option<String> {
+    BILLING_TAG.try_with(|t| t.clone()).ok()
+}
+
+/// Provider-side metadata of a completion response, useful to reference a request in
+/// support tickets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseMeta {
+    pub id: String,
+    pub created: u32,
+    pub model: String,
+    pub system_fingerprint: Option<String>,
+}
+
+impl From<&CreateChatCompletionResponse> for ResponseMeta {
+    // The fingerprint is deprecated upstream but still returned by many providers.
+    #[allow(deprecated)]
+    fn from(resp: &CreateChatCompletionResponse) -> Self {
+        Self {
+            id: resp.id.clone(),
+            created: resp.created,
+            model: resp.model.clone(),
+            system_fingerprint: resp.system_fingerprint.clone(),
         }
     }
 }
 
+/// Sent to the hooks registered with [`LLMInner::on_billing`].
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ModelBilling {
+pub struct BillingEvent {
+    pub entry: BillingEntry,
+    /// Total spend after this entry.
     pub current: f64,
     pub cap: f64,
+    pub in_cap: bool,
 }
 
-impl Display for ModelBilling {
+pub type BillingHook = Arc<dyn Fn(&BillingEvent) + Send + Sync>;
+
+#[derive(Default)]
+pub struct BillingHooks(std::sync::RwLock<Vec<BillingHook>>);
+
+impl Debug for BillingHooks {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("Billing({:.4}/{})", self.current, self.cap))
+        let count = self.0.read().map(|hooks| hooks.len()).unwrap_or_default();
+        write!(f, "BillingHooks({})", count)
     }
 }
 
-impl ModelBilling {
-    pub fn new(cap: f64) -> Self {
-        Self { current: 0.0, cap }
+impl BillingHooks {
+    fn push(&self, hook: BillingHook) {
+        if let Ok(mut hooks) = self.0.write() {
+            hooks.push(hook);
+        }
     }
 
-    pub fn in_cap(&self) -> bool {
-        self.current <= self.cap
+    fn notify(&self, event: &BillingEvent) {
+        let hooks = match self.0.read() {
+            Ok(hooks) => hooks.clone(),
+            Err(_) => return,
+        };
+        for hook in hooks.iter() {
+            hook(event);
+        }
     }
+}
 
-    pub fn input_tokens(
-        &mut self,
-        model: &OpenAIModel,
-        input_count: u64,
-        cached_count: u64,
-    ) -> Result<()> {
-        let pricing = model.pricing();
+/// Tokens of a response usage split by price, each token counted once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageTokens {
+    /// Prompt tokens not served from the cache.
+    pub input: u64,
+    pub cached: u64,
+    /// Completion tokens without the reasoning ones.
+    pub output: u64,
+    pub reasoning: u64,
+}
 
-        let cached_price = if let Some(cached) = pricing.cached_input_tokens {
-            cached
-        } else {
+impl From<&CompletionUsage> for UsageTokens {
+    fn from(usage: &CompletionUsage) -> Self {
+        let cached = usage
+            .prompt_tokens_details
+            .as_ref()
+            .and_then(|v| v.cached_tokens)
+            .unwrap_or_default()
+            .min(usage.prompt_tokens);
+        let reasoning = usage
+            .completion_tokens_details
+            .as_ref()
+            .and_then(|v| v.reasoning_tokens)
+            .unwrap_or_default()
+            .min(usage.completion_tokens);
+        Self {
+            input: (usage.prompt_tokens - cached) as u64,
+            cached: cached as u64,
+            output: (usage.completion_tokens - reasoning) as u64,
+            reasoning: reasoning as u64,
+        }
+    }
+}
+
+/// One billed completion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BillingEntry {
+    pub time: DateTime<Utc>,
+    pub model: String,
+    pub prefix: String,
+    /// Attribution tag (team/feature name) set by [`LLMInner::with_billing_tag`]
+    pub tag: Option<String>,
+    pub input_tokens: u64,
+    pub cached_tokens: u64,
+    /// Completion tokens, reasoning tokens included.
+    pub output_tokens: u64,
+    pub reasoning_tokens: u64,
+    /// Part of the prompt spent on tool definitions, estimated by the token counter.
+    #[serde(default)]
+    pub tool_tokens: u64,
+    pub cost: f64,
+    #[serde(default)]
+    pub response: Option<ResponseMeta>,
+}
+
+fn one() -> u64 {
+    1
+}
+
+/// Totals of the billed entries sharing a tag and prefix. Ledgers saved before the
+/// roll up hold single entries, read as totals of one request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LedgerTotals {
+    pub tag: Option<String>,
+    pub prefix: String,
+    #[serde(default = "one")]
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub cached_tokens: u64,
+    pub output_tokens: u64,
+    pub reasoning_tokens: u64,
+    pub tool_tokens: u64,
+    pub cost: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Display)]
+pub enum QuotaPeriod {
+    #[display("hourly")]
+    Hour,
+    #[display("daily")]
+    Day,
+    #[display("monthly")]
+    Month,
+}
+
+impl QuotaPeriod {
+    /// Start of the period containing `now`, in UTC wall clock.
+    pub fn start_of(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let date = now.date_naive();
+        let start = match self {
+            Self::Hour => date.and_hms_opt(now.hour(), 0, 0),
+            Self::Day => date.and_hms_opt(0, 0, 0),
+            Self::Month => date.with_day(1).and_then(|d| d.and_hms_opt(0, 0, 0)),
+        };
+        start.expect("valid period start").and_utc()
+    }
+}
+
+/// A budget cap which resets automatically at the start of every period.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaWindow {
+    pub period: QuotaPeriod,
+    pub cap: f64,
+    pub current: f64,
+    pub start: DateTime<Utc>,
+}
+
+impl QuotaWindow {
+    pub fn new(period: QuotaPeriod, cap: f64) -> Self {
+        Self {
+            period,
+            cap,
+            current: 0.0,
+            start: period.start_of(Utc::now()),
+        }
+    }
+
+    fn roll(&mut self, now: DateTime<Utc>) {
+        let start = self.period.start_of(now);
+        if start != self.start {
+            self.start = start;
+            self.current = 0.0;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelBilling {
+    pub current: f64,
+    pub cap: f64,
+    /// Entries rolled up per tag and prefix, so the state stays small. Set
+    /// `--openai-billing-ledger` to keep every entry.
+    #[serde(default)]
+    pub ledger: Vec<LedgerTotals>,
+    #[serde(default)]
+    pub windows: Vec<QuotaWindow>,
+    /// Reasoning tokens billed so far, already included in the output tokens.
+    #[serde(default)]
+    pub reasoning_tokens: u64,
+}
+
+impl Display for ModelBilling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Billing({:.4}/{})", self.current, self.cap))
+    }
+}
+
+impl ModelBilling {
+    pub fn new(cap: f64) -> Self {
+        Self {
+            current: 0.0,
+            cap,
+            ledger: vec![],
+            windows: vec![],
+            reasoning_tokens: 0,
+        }
+    }
+
+    /// Add a rolling cap, e.g. `with_window(QuotaPeriod::Day, 5.0)` for max $5/day.
+    pub fn with_window(mut self, period: QuotaPeriod, cap: f64) -> Self {
+        self.windows.push(QuotaWindow::new(period, cap));
+        self
+    }
+
+    /// Read a state written by [`Self::save`].
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// Write the state atomically, so a crash never leaves a torn file.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(self)?).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    /// Carry the spend of `saved` over, caps stay as configured.
+    pub fn restore(&mut self, saved: ModelBilling) {
+        self.current = saved.current;
+        self.ledger.clear();
+        for totals in saved.ledger {
+            self.merge_totals(totals);
+        }
+        self.reasoning_tokens = saved.reasoning_tokens;
+        for window in self.windows.iter_mut() {
+            if let Some(old) = saved.windows.iter().find(|w| w.period == window.period) {
+                window.current = old.current;
+                window.start = old.start;
+            }
+        }
+    }
+
+    fn charge(&mut self, usd: f64) {
+        let now = Utc::now();
+        self.current += usd;
+        for window in self.windows.iter_mut() {
+            window.roll(now);
+            window.current += usd;
+        }
+    }
+
+    fn check_cap(&self) -> Result<()> {
+        if self.current > self.cap {
+            return Err(eyre!("cap {} reached, current {}", self.cap, self.current));
+        }
+        let now = Utc::now();
+        for window in self.windows.iter() {
+            // An expired window resets on the next charge.
+            if window.current > window.cap && window.period.start_of(now) == window.start {
+                return Err(eyre!(
+                    "{} cap {} reached, current {} since {}",
+                    window.period,
+                    window.cap,
+                    window.current,
+                    window.start
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `entry` to the totals of its tag and prefix.
+    pub fn add_to_ledger(&mut self, entry: &BillingEntry) {
+        self.merge_totals(LedgerTotals {
+            tag: entry.tag.clone(),
+            prefix: entry.prefix.clone(),
+            requests: 1,
+            input_tokens: entry.input_tokens,
+            cached_tokens: entry.cached_tokens,
+            output_tokens: entry.output_tokens,
+            reasoning_tokens: entry.reasoning_tokens,
+            tool_tokens: entry.tool_tokens,
+            cost: entry.cost,
+        });
+    }
+
+    fn merge_totals(&mut self, other: LedgerTotals) {
+        let Some(totals) = self
+            .ledger
+            .iter_mut()
+            .find(|t| t.tag == other.tag && t.prefix == other.prefix)
+        else {
+            self.ledger.push(other);
+            return;
+        };
+        totals.requests += other.requests;
+        totals.input_tokens += other.input_tokens;
+        totals.cached_tokens += other.cached_tokens;
+        totals.output_tokens += other.output_tokens;
+        totals.reasoning_tokens += other.reasoning_tokens;
+        totals.tool_tokens += other.tool_tokens;
+        totals.cost += other.cost;
+    }
+
+    /// Total cost per attribution tag, untagged calls are under the empty tag.
+    pub fn cost_by_tag(&self) -> BTreeMap<String, f64> {
+        let mut costs = BTreeMap::new();
+        for entry in self.ledger.iter() {
+            *costs
+                .entry(entry.tag.clone().unwrap_or_default())
+                .or_default() += entry.cost;
+        }
+        costs
+    }
+
+    /// Share of the billed prompt tokens spent on tool definitions, a high one means
+    /// the toolbox schema drives the cost.
+    pub fn tool_token_share(&self) -> f64 {
+        let (tools, prompt) = self.ledger.iter().fold((0, 0), |(tools, prompt), e| {
+            (
+                tools + e.tool_tokens,
+                prompt + e.input_tokens + e.cached_tokens,
+            )
+        });
+        if prompt == 0 {
+            0.0
+        } else {
+            tools as f64 / prompt as f64
+        }
+    }
+
+    pub fn in_cap(&self) -> bool {
+        self.check_cap().is_ok()
+    }
+
+    pub fn input_tokens(
+        &mut self,
+        model: &OpenAIModel,
+        input_count: u64,
+        cached_count: u64,
+    ) -> Result<()> {
+        let pricing = model.pricing();
+
+        let cached_price = if let Some(cached) = pricing.cached_input_tokens {
+            cached
+        } else {
             pricing.input_tokens
         };
 
@@ -367,15 +1311,30 @@ impl ModelBilling {
             raw_input_usd,
             input_count
         );
-        self.current += cached_usd + raw_input_usd;
+        self.charge(cached_usd + raw_input_usd);
+        self.check_cap()
+    }
 
-        if self.in_cap() {
-            Ok(())
-        } else {
-            Err(eyre!("cap {} reached, current {}", self.cap, self.current))
-        }
+    /// Bill a whole response usage and return its cost. Cached prompt tokens get the
+    /// cached price, reasoning tokens the output price.
+    pub fn usage(&mut self, model: &OpenAIModel, usage: &CompletionUsage) -> (f64, Result<()>) {
+        let tokens = UsageTokens::from(usage);
+        let before = self.current;
+        let input_res = self.input_tokens(model, tokens.input, tokens.cached);
+        let output_res = self.output_tokens(model, tokens.output, tokens.reasoning);
+        self.reasoning_tokens += tokens.reasoning;
+        (self.current - before, input_res.and(output_res))
+    }
+
+    pub fn embedding_tokens(&mut self, model: &EmbeddingModel, count: u64) -> Result<()> {
+        let usd = model.pricing() * (count as f64) / 1e6;
+        log::debug!("Embedding token usage: {:.4} USD, {} tokens", usd, count);
+        self.charge(usd);
+        self.check_cap()
     }
 
+    /// Bill `count` output tokens plus `reasoning` tokens, both at the output price.
+    /// `count` must not include the reasoning tokens, see [`UsageTokens`].
     pub fn output_tokens(&mut self, model: &OpenAIModel, count: u64, reasoning: u64) -> Result<()> {
         let pricing = model.pricing();
 
@@ -388,14 +1347,8 @@ impl ModelBilling {
             reason_usd,
             reasoning
         );
-        self.current += output_usd;
-        self.current += reason_usd;
-
-        if self.in_cap() {
-            Ok(())
-        } else {
-            Err(eyre!("cap {} reached, current {}", self.cap, self.current))
-        }
+        self.charge(output_usd + reason_usd);
+        self.check_cap()
     }
 }
 
@@ -420,8 +1373,36 @@ pub struct LLMInner {
     pub llm_debug: Option<PathBuf>,
     pub llm_debug_index: AtomicU64,
     pub default_settings: LLMSettings,
+    pub provider: Provider,
+    /// Project memory file content injected into the system prompt of every request
+    pub memory: Option<String>,
+    pub redactor: Option<Redactor>,
+    pub hedge: Option<HedgeSettings>,
+    pub stats: RwLock<LLMStats>,
+    pub length_warn_ratio: f64,
+    pub dump_options: DumpOptions,
+    /// Header carrying an idempotency key shared by all retries of one request, for
+    /// gateways supporting it.
+    pub idempotency_header: Option<HeaderName>,
+    pub token_counter: Box<dyn TokenCounter>,
+    pub usage_fallback: UsageFallback,
+    /// JSONL file receiving a [`GenAIRecord`] per completion call.
+    pub genai_log: Option<PathBuf>,
+    /// Billing state saved after every charge and restored on startup.
+    pub billing_state: Option<PathBuf>,
+    pub billing_save: Mutex<()>,
+    /// JSONL file receiving every [`BillingEntry`].
+    pub billing_ledger: Option<PathBuf>,
+    pub role_mapping: RoleMapping,
+    pub billing_hooks: BillingHooks,
+    pub embedding_model: EmbeddingModel,
+    /// Client for embeddings when they are served elsewhere, e.g. another Azure deployment.
+    pub embedding_client: Option<LLMClient>,
+    pub rate_limit: RwLock<RateLimitInfo>,
+    pub spend_limiter: Option<SpendLimiter>,
 }
 
+#[doc(hidden)]
 pub fn completion_to_role(msg: &ChatCompletionRequestMessage) -> &'static str {
     match msg {
         ChatCompletionRequestMessage::Assistant(_) => "ASSISTANT",
@@ -433,6 +1414,7 @@ pub fn completion_to_role(msg: &ChatCompletionRequestMessage) -> &'static str {
     }
 }
 
+#[doc(hidden)]
 pub fn toolcall_to_string(t: &ChatCompletionMessageToolCalls) -> String {
     match t {
         ChatCompletionMessageToolCalls::Function(t) => {
@@ -450,7 +1432,96 @@ pub fn toolcall_to_string(t: &ChatCompletionMessageToolCalls) -> String {
     }
 }
 
+/// How control characters (other than newlines and tabs) are written to debug dumps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display)]
+pub enum ControlChars {
+    #[default]
+    #[display("keep")]
+    Keep,
+    #[display("strip")]
+    Strip,
+    #[display("escape")]
+    Escape,
+}
+
+impl FromStr for ControlChars {
+    type Err = color_eyre::Report;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keep" => Ok(Self::Keep),
+            "strip" => Ok(Self::Strip),
+            "escape" => Ok(Self::Escape),
+            _ => Err(eyre!("unknown control chars handling: {}", s)),
+        }
+    }
+}
+
+/// What to bill when a provider returns no usage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display)]
+pub enum UsageFallback {
+    /// Bill nothing, only warn.
+    #[default]
+    #[display("zero")]
+    Zero,
+    /// Bill the tokens counted by the token counter.
+    #[display("estimate")]
+    Estimate,
+    #[display("error")]
+    Error,
+}
+
+impl FromStr for UsageFallback {
+    type Err = color_eyre::Report;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "zero" => Ok(Self::Zero),
+            "estimate" => Ok(Self::Estimate),
+            "error" => Ok(Self::Error),
+            _ => Err(eyre!("unknown usage fallback: {}", s)),
+        }
+    }
+}
+
+/// Escaping and limits applied to each message written to the debug dumps.
+#[derive(Debug, Clone, Default)]
+pub struct DumpOptions {
+    pub control_chars: ControlChars,
+    /// Longer messages are cut, with the number of omitted chars noted.
+    pub max_message_chars: Option<usize>,
+}
+
+impl DumpOptions {
+    pub fn apply(&self, s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        let mut chars = s.chars();
+        for (taken, c) in chars.by_ref().enumerate() {
+            if self.max_message_chars.is_some_and(|max| taken >= max) {
+                let omitted = 1 + chars.count();
+                out += &format!("<truncated chars=\"{}\"/>", omitted);
+                break;
+            }
+            if c.is_control() && c != '\n' && c != '\t' {
+                match self.control_chars {
+                    ControlChars::Keep => out.push(c),
+                    ControlChars::Strip => {}
+                    ControlChars::Escape => out.extend(c.escape_unicode()),
+                }
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+}
+
 pub fn response_to_string(resp: &ChatCompletionResponseMessage) -> String {
+    response_to_string_with(resp, &DumpOptions::default())
+}
+
+pub fn response_to_string_with(
+    resp: &ChatCompletionResponseMessage,
+    options: &DumpOptions,
+) -> String {
     let mut s = String::new();
     if let Some(content) = resp.content.as_ref() {
         s += content;
@@ -458,7 +1529,7 @@ pub fn response_to_string(resp: &ChatCompletionResponseMessage) -> String {
     }
 
     if let Some(tools) = resp.tool_calls.as_ref() {
-        s += &tools.iter().map(|t| toolcall_to_string(t)).join("\n");
+        s += &tools.iter().map(toolcall_to_string).join("\n");
     }
 
     if let Some(refusal) = &resp.refusal {
@@ -468,10 +1539,17 @@ pub fn response_to_string(resp: &ChatCompletionResponseMessage) -> String {
 
     let role = resp.role.to_string().to_uppercase();
 
-    format!("<{}>\n{}\n</{}>\n", &role, s, &role)
+    format!("<{}>\n{}\n</{}>\n", &role, options.apply(&s), &role)
 }
 
 pub fn completion_to_string(msg: &ChatCompletionRequestMessage) -> String {
+    completion_to_string_with(msg, &DumpOptions::default())
+}
+
+pub fn completion_to_string_with(
+    msg: &ChatCompletionRequestMessage,
+    options: &DumpOptions,
+) -> String {
     const CONT: &str = "<cont/>\n";
     const NONE: &str = "<none/>\n";
     let role = completion_to_role(msg);
@@ -499,7 +1577,7 @@ pub fn completion_to_string(msg: &ChatCompletionRequestMessage) -> String {
                 .tool_calls
                 .iter()
                 .flatten()
-                .map(|t| toolcall_to_string(t))
+                .map(toolcall_to_string)
                 .join("\n");
             format!("{}\n{}", msg, tool_calls)
         }
@@ -551,7 +1629,220 @@ pub fn completion_to_string(msg: &ChatCompletionRequestMessage) -> String {
         },
     };
 
-    format!("<{}>\n{}\n</{}>\n", role, content, role)
+    format!("<{}>\n{}\n</{}>\n", role, options.apply(&content), role)
+}
+
+/// Text content of `msg` as sent to the model, parts joined by newlines. Images,
+/// audio, files and tool calls are left out.
+pub(crate) fn message_text(msg: &ChatCompletionRequestMessage) -> String {
+    match msg {
+        ChatCompletionRequestMessage::Assistant(ass) => match ass.content.as_ref() {
+            Some(ChatCompletionRequestAssistantMessageContent::Text(t)) => t.clone(),
+            Some(ChatCompletionRequestAssistantMessageContent::Array(arr)) => arr
+                .iter()
+                .filter_map(|v| match v {
+                    ChatCompletionRequestAssistantMessageContentPart::Text(t) => {
+                        Some(t.text.as_str())
+                    }
+                    ChatCompletionRequestAssistantMessageContentPart::Refusal(_) => None,
+                })
+                .join("\n"),
+            None => String::new(),
+        },
+        ChatCompletionRequestMessage::Developer(dev) => match &dev.content {
+            ChatCompletionRequestDeveloperMessageContent::Text(t) => t.clone(),
+            ChatCompletionRequestDeveloperMessageContent::Array(arr) => arr
+                .iter()
+                .map(|v| match v {
+                    ChatCompletionRequestDeveloperMessageContentPart::Text(t) => t.text.as_str(),
+                })
+                .join("\n"),
+        },
+        ChatCompletionRequestMessage::Function(f) => f.content.clone().unwrap_or_default(),
+        ChatCompletionRequestMessage::System(sys) => match &sys.content {
+            ChatCompletionRequestSystemMessageContent::Text(t) => t.clone(),
+            ChatCompletionRequestSystemMessageContent::Array(arr) => arr
+                .iter()
+                .map(|v| match v {
+                    ChatCompletionRequestSystemMessageContentPart::Text(t) => t.text.as_str(),
+                })
+                .join("\n"),
+        },
+        ChatCompletionRequestMessage::Tool(tool) => match &tool.content {
+            ChatCompletionRequestToolMessageContent::Text(t) => t.clone(),
+            ChatCompletionRequestToolMessageContent::Array(arr) => arr
+                .iter()
+                .map(|v| match v {
+                    ChatCompletionRequestToolMessageContentPart::Text(t) => t.text.as_str(),
+                })
+                .join("\n"),
+        },
+        ChatCompletionRequestMessage::User(usr) => match &usr.content {
+            ChatCompletionRequestUserMessageContent::Text(t) => t.clone(),
+            ChatCompletionRequestUserMessageContent::Array(arr) => arr
+                .iter()
+                .filter_map(|v| match v {
+                    ChatCompletionRequestUserMessageContentPart::Text(t) => Some(t.text.as_str()),
+                    _ => None,
+                })
+                .join("\n"),
+        },
+    }
+}
+
+pub const DEBUG_INDEX_FILE: &str = "index.jsonl";
+pub const DEBUG_SESSION_FILE: &str = "session.json";
+pub const DEBUG_STATS_FILE: &str = "stats.jsonl";
+
+/// Who wrote a debug directory, stored as [`DEBUG_SESSION_FILE`] in it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DebugSession {
+    pub id: String,
+    pub pid: u32,
+    pub started: DateTime<Utc>,
+    pub prefix: String,
+    pub model: String,
+}
+
+/// Create a fresh session directory `<pid>-<session id>-<prefix>` under `root`, so
+/// processes and LLMs sharing the root never write into the same directory.
+pub fn create_debug_session(
+    root: &Path,
+    prefix: &str,
+    model: &OpenAIModel,
+) -> Result<PathBuf, PromptError> {
+    std::fs::create_dir_all(root)?;
+    loop {
+        let id = new_session_id();
+        let pid = std::process::id();
+        let path = root.join(format!("{}-{}-{}", pid, &id, prefix));
+        // create_dir fails if the directory exists, which makes the claim atomic.
+        match std::fs::create_dir(&path) {
+            Ok(()) => {
+                let session = DebugSession {
+                    id,
+                    pid,
+                    started: Utc::now(),
+                    prefix: prefix.to_string(),
+                    model: model.to_string(),
+                };
+                std::fs::write(
+                    path.join(DEBUG_SESSION_FILE),
+                    serde_json::to_vec_pretty(&session)?,
+                )?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Metadata of one completion dumped in the debug directory, stored as a line of
+/// [`DEBUG_INDEX_FILE`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DumpIndexEntry {
+    pub index: u64,
+    /// The xml dump, the json dump shares the same stem.
+    pub file: String,
+    pub time: DateTime<Utc>,
+    pub prefix: String,
+    pub tag: Option<String>,
+    pub model: String,
+    pub response_id: String,
+    pub cost: f64,
+}
+
+impl DumpIndexEntry {
+    async fn append_to(&self, dump_fp: &Path) -> Result<(), PromptError> {
+        append_jsonl(&dump_fp.with_file_name(DEBUG_INDEX_FILE), self).await
+    }
+}
+
+async fn append_jsonl<T: Serialize>(fpath: &Path, t: &T) -> Result<(), PromptError> {
+    let mut line = serde_json::to_string(t)?;
+    line.push('\n');
+    // One append per line keeps concurrent writers from interleaving entries.
+    let mut fp = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(fpath)
+        .await?;
+    fp.write_all(line.as_bytes()).await?;
+    fp.flush().await?;
+    Ok(())
+}
+
+// Fill the fields of the `llm.completion` span and trace each tool call in it.
+fn record_completion_span(
+    resp: &CreateChatCompletionResponse,
+    usage: Option<&CompletionUsage>,
+    cost: f64,
+) {
+    let span = Span::current();
+    if let Some(usage) = usage {
+        span.record("input_tokens", usage.prompt_tokens);
+        span.record("output_tokens", usage.completion_tokens);
+        let tokens = UsageTokens::from(usage);
+        span.record("cached_tokens", tokens.cached);
+        span.record("reasoning_tokens", tokens.reasoning);
+    }
+    span.record("cost", cost);
+    let Some(choice) = resp.choices.first() else {
+        return;
+    };
+    if let Some(reason) = choice.finish_reason.as_ref() {
+        span.record("finish_reason", tracing::field::debug(reason));
+    }
+    let calls = choice.message.tool_calls.as_deref().unwrap_or_default();
+    span.record("tool_calls", calls.len());
+    for call in calls {
+        if let ChatCompletionMessageToolCalls::Function(call) = call {
+            tracing::debug!(
+                tool = %call.function.name,
+                id = %call.id,
+                arguments_len = call.function.arguments.len(),
+                "tool call"
+            );
+        }
+    }
+}
+
+/// Read the debug index of a debug directory, skipping malformed lines.
+pub async fn load_debug_index(dir: &Path) -> Result<Vec<DumpIndexEntry>, PromptError> {
+    let content = match tokio::fs::read_to_string(dir.join(DEBUG_INDEX_FILE)).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|l| serde_json::from_str(l).ok())
+        .collect())
+}
+
+/// Read the stats of a debug directory, skipping malformed lines. See
+/// [`crate::stats::context_growth`] to analyze them.
+pub async fn load_dump_stats(dir: &Path) -> Result<Vec<DumpStats>, PromptError> {
+    let content = match tokio::fs::read_to_string(dir.join(DEBUG_STATS_FILE)).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|l| serde_json::from_str(l).ok())
+        .collect())
+}
+
+/// Load the request from a json debug dump, which holds the request on its first line.
+pub async fn load_debug_request(fpath: &Path) -> Result<CreateChatCompletionRequest, PromptError> {
+    let content = tokio::fs::read_to_string(fpath).await?;
+    let line = content
+        .lines()
+        .next()
+        .ok_or_eyre(eyre!("empty debug dump {:?}", fpath))?;
+    Ok(serde_json::from_str(line)?)
 }
 
 impl LLMInner {
@@ -586,6 +1877,8 @@ impl LLMInner {
     async fn save_llm_user(
         fpath: &PathBuf,
         user_msg: &CreateChatCompletionRequest,
+        headers: &HeaderMap,
+        options: &DumpOptions,
     ) -> Result<(), PromptError> {
         let mut fp = tokio::fs::OpenOptions::new()
             .create(true)
@@ -594,8 +1887,16 @@ impl LLMInner {
             .open(&fpath)
             .await?;
         fp.write_all(b"=====================\n<Request>\n").await?;
+        for (name, value) in headers.iter() {
+            let header = format!(
+                "<header name=\"{}\">{}</header>\n",
+                name,
+                value.to_str().unwrap_or_default()
+            );
+            fp.write_all(header.as_bytes()).await?;
+        }
         for it in user_msg.messages.iter() {
-            let msg = completion_to_string(it);
+            let msg = completion_to_string_with(it, options);
             fp.write_all(msg.as_bytes()).await?;
         }
 
@@ -641,7 +1942,11 @@ impl LLMInner {
         Ok(())
     }
 
-    async fn save_llm_resp(fpath: &PathBuf, resp: &CreateChatCompletionResponse) -> Result<()> {
+    async fn save_llm_resp(
+        fpath: &PathBuf,
+        resp: &CreateChatCompletionResponse,
+        options: &DumpOptions,
+    ) -> Result<()> {
         let mut fp = tokio::fs::OpenOptions::new()
             .create(false)
             .append(true)
@@ -650,7 +1955,7 @@ impl LLMInner {
             .await?;
         fp.write_all(b"=====================\n<Response>\n").await?;
         for it in &resp.choices {
-            let msg = response_to_string(&it.message);
+            let msg = response_to_string_with(&it.message, options);
             fp.write_all(msg.as_bytes()).await?;
         }
         fp.write_all(b"\n</Response>\n=====================\n")
@@ -662,16 +1967,108 @@ impl LLMInner {
         Ok(())
     }
 
-    fn on_llm_debug(&self, prefix: &str) -> Option<PathBuf> {
+    async fn save_llm_error(fpath: &PathBuf, err: &PromptError) -> Result<()> {
+        let mut fp = tokio::fs::OpenOptions::new()
+            .create(false)
+            .append(true)
+            .open(&fpath)
+            .await?;
+        let mut s = format!("=====================\n<Error>\n{}\n", err);
+        if let PromptError::Provider(p) = err {
+            if let Some(status) = p.status {
+                s += &format!("<status>{}</status>\n", status);
+            }
+            if let Some(id) = p.request_id.as_ref() {
+                s += &format!("<request-id>{}</request-id>\n", id);
+            }
+            s += &format!("<body>\n{}\n</body>\n", p.body);
+        }
+        s += "</Error>\n=====================\n";
+        fp.write_all(s.as_bytes()).await?;
+        fp.flush().await?;
+        Ok(())
+    }
+
+    /// Run `fut` with every completion inside it attributed to `tag` in the billing
+    /// ledger, independent of the debug prefix.
+    pub async fn with_billing_tag<F: Future>(&self, tag: &str, fut: F) -> F::Output {
+        BILLING_TAG.scope(tag.to_string(), fut).await
+    }
+
+    /// Wait while the spend rate limit is exceeded. Nothing is reserved up front:
+    /// callers waiting at the same time see the same debt and all resume together, so
+    /// the spend can overshoot the rate by one completion per concurrent caller before
+    /// their costs are charged.
+    pub async fn throttle_spend(&self) {
+        let Some(limiter) = self.spend_limiter.as_ref() else {
+            return;
+        };
+        let wait = limiter.wait_time();
+        if !wait.is_zero() {
+            warn!(
+                "Spending faster than {} USD per hour, waiting {:?}",
+                limiter.usd_per_hour, wait
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Run `fut` with the HTTP exchanges of every completion inside it bounded by
+    /// `timeout` instead of `llm_http_timeout`, e.g. for long reasoning calls.
+    pub async fn with_http_timeout<F: Future>(&self, timeout: Duration, fut: F) -> F::Output {
+        HTTP_TIMEOUT.scope(timeout, fut).await
+    }
+
+    fn on_llm_debug(&self, prefix: &str) -> Option<(u64, PathBuf)> {
         if let Some(output_folder) = self.llm_debug.as_ref() {
             let idx = self.llm_debug_index.fetch_add(1, Ordering::SeqCst);
             let fpath = output_folder.join(format!("{}-{:0>12}.xml", prefix, idx));
-            Some(fpath)
+            Some((idx, fpath))
         } else {
             None
         }
     }
 
+    /// Entries of the debug index matching `filter`, in completion order.
+    pub async fn find_dumps<F: Fn(&DumpIndexEntry) -> bool>(
+        &self,
+        filter: F,
+    ) -> Result<Vec<DumpIndexEntry>, PromptError> {
+        let output_folder = self
+            .llm_debug
+            .as_ref()
+            .ok_or_eyre(eyre!("llm debug is not enabled"))?;
+        Ok(load_debug_index(output_folder)
+            .await?
+            .into_iter()
+            .filter(|e| filter(e))
+            .collect())
+    }
+
+    /// Rebuild the exact request sent at the given debug index from the json dump,
+    /// so it can be re-sent, possibly with a different model.
+    pub async fn request_at(&self, index: u64) -> Result<CreateChatCompletionRequest, PromptError> {
+        let output_folder = self
+            .llm_debug
+            .as_ref()
+            .ok_or_eyre(eyre!("llm debug is not enabled"))?;
+        let suffix = format!("-{:0>12}.json", index);
+
+        let mut entries = tokio::fs::read_dir(output_folder).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let fname = entry.file_name();
+            if fname.to_string_lossy().ends_with(&suffix) {
+                return load_debug_request(&entry.path()).await;
+            }
+        }
+
+        Err(PromptError::Other(eyre!(
+            "no debug dump for index {} in {:?}",
+            index,
+            output_folder
+        )))
+    }
+
     // we use t/s to estimate a timeout to avoid infinite repeating
     pub async fn prompt_once_with_retry(
         &self,
@@ -688,34 +2085,49 @@ impl LLMInner {
         let user = ChatCompletionRequestUserMessageArgs::default()
             .content(user_msg)
             .build()?;
+        let req = self
+            .request_args(&settings, prefix)
+            .messages(vec![sys.into(), user.into()])
+            .build()?;
+
+        self.complete_with_settings(&req, prefix, &settings).await
+    }
+
+    /// Request builder with model and sampling settings applied, messages left to the caller.
+    pub(crate) fn request_args(
+        &self,
+        settings: &LLMSettings,
+        prefix: Option<&str>,
+    ) -> CreateChatCompletionRequestArgs {
         let mut req = CreateChatCompletionRequestArgs::default();
-        req.messages(vec![sys.into(), user.into()])
-            .model(self.model.to_string())
-            .temperature(settings.llm_temperature)
-            .presence_penalty(settings.llm_presence_penalty)
+        req.model(self.model.to_string())
             .max_completion_tokens(settings.llm_max_completion_tokens);
 
-        if let Some(tc) = settings.llm_tool_choice {
+        if self.model.accepts_sampling() {
+            if let Some(temperature) = settings.llm_temperature {
+                req.temperature(temperature);
+            }
+            if let Some(penalty) = settings.llm_presence_penalty {
+                req.presence_penalty(penalty);
+            }
+        } else if settings.llm_temperature.is_some() || settings.llm_presence_penalty.is_some() {
+            debug!(
+                "{} rejects sampling parameters, not sending them",
+                &self.model
+            );
+        }
+
+        if let Some(tc) = settings.llm_tool_choice.clone() {
             req.tool_choice(tc);
         }
-        if let Some(effort) = settings.reasoning_effort {
+        if let Some(effort) = settings.reasoning_effort.clone() {
             req.reasoning_effort(effort.0);
         }
         if let Some(prefix) = prefix {
             req.prompt_cache_key(prefix.to_string());
         }
-
-        let req = req.build()?;
-
-        let timeout = if settings.llm_prompt_timeout == 0 {
-            Duration::MAX
-        } else {
-            Duration::from_secs(settings.llm_prompt_timeout)
-        };
-
-        self.complete_once_with_retry(&req, prefix, Some(timeout), Some(settings.llm_retry))
-            .await
-    }
+        req
+    }
 
     pub async fn complete_once_with_retry(
         &self,
@@ -724,39 +2136,233 @@ impl LLMInner {
         timeout: Option<Duration>,
         retry: Option<u64>,
     ) -> Result<CreateChatCompletionResponse, PromptError> {
-        let timeout = if let Some(timeout) = timeout {
-            timeout
-        } else {
-            Duration::MAX
+        let retry = retry.unwrap_or(u64::MAX);
+        let policy = match self.default_settings.llm_retry_cost {
+            Some(max_extra_usd) => RetryPolicy::Cost {
+                max_extra_usd,
+                max_attempts: retry,
+            },
+            None => RetryPolicy::Attempts(retry),
         };
+        self.complete_with_retry_policy(
+            req,
+            prefix,
+            timeout,
+            policy,
+            self.default_settings.backoff(),
+        )
+        .await
+    }
 
-        let retry = if let Some(retry) = retry {
-            retry
-        } else {
-            u64::MAX
+    /// Estimated cost of the prompt of `req`, counted by the token counter.
+    pub fn estimate_input_cost(&self, req: &CreateChatCompletionRequest) -> f64 {
+        let tokens = self.token_counter.count_messages(&req.messages);
+        tokens as f64 * self.model.pricing().input_tokens / 1e6
+    }
+
+    /// Context window of the model, overridden by `llm_context_window`.
+    pub fn context_window(&self) -> Option<u64> {
+        self.default_settings
+            .llm_context_window
+            .or(self.model.context_window())
+    }
+
+    /// Output limit of the model, overridden by `llm_max_output_tokens`, or the whole
+    /// context window for an unknown model with only its context window set.
+    pub fn max_output_tokens(&self) -> Option<u64> {
+        self.default_settings
+            .llm_max_output_tokens
+            .or(self.model.max_output_tokens())
+            .or(self.context_window())
+    }
+
+    // Fit max_completion_tokens in the context window left by the prompt and tools.
+    fn auto_max_tokens(&self, req: &mut CreateChatCompletionRequest) {
+        let (Some(context_window), Some(max_output_tokens)) =
+            (self.context_window(), self.max_output_tokens())
+        else {
+            debug!(
+                "No context window known for {}, keeping max tokens",
+                &self.model
+            );
+            return;
+        };
+        let prompt = (self.token_counter.count_messages(&req.messages)
+            + self
+                .token_counter
+                .count_tools(req.tools.as_deref().unwrap_or_default())) as u64;
+        let margin = self.default_settings.llm_auto_max_tokens_margin as u64;
+        let left = context_window.saturating_sub(prompt + margin);
+        if left == 0 {
+            warn!(
+                "Prompt of {} tokens leaves no room in the {} context window of {}",
+                prompt, context_window, &self.model
+            );
+        }
+        let max_tokens = left.min(max_output_tokens).clamp(1, u32::MAX as u64) as u32;
+        debug!(
+            "Auto max tokens {} for a prompt of {} tokens",
+            max_tokens, prompt
+        );
+        req.max_completion_tokens = Some(max_tokens);
+    }
+
+    /// Complete with the timeout, retries and partial salvage of `settings`.
+    pub async fn complete_with_settings(
+        &self,
+        req: &CreateChatCompletionRequest,
+        prefix: Option<&str>,
+        settings: &LLMSettings,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        self.complete_with_retries(
+            req,
+            prefix,
+            Some(settings.prompt_timeout()),
+            settings.retry_policy(),
+            settings.backoff(),
+            settings.llm_salvage_partial,
+        )
+        .await
+    }
+
+    pub async fn complete_with_retry_policy(
+        &self,
+        req: &CreateChatCompletionRequest,
+        prefix: Option<&str>,
+        timeout: Option<Duration>,
+        policy: RetryPolicy,
+        backoff: RetryBackoff,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        self.complete_with_retries(
+            req,
+            prefix,
+            timeout,
+            policy,
+            backoff,
+            self.default_settings.llm_salvage_partial,
+        )
+        .await
+    }
+
+    #[tracing::instrument(
+        name = "llm.request",
+        skip_all,
+        fields(model = %self.model, prefix = prefix.unwrap_or("llm"), attempts = Empty)
+    )]
+    async fn complete_with_retries(
+        &self,
+        req: &CreateChatCompletionRequest,
+        prefix: Option<&str>,
+        timeout: Option<Duration>,
+        policy: RetryPolicy,
+        backoff: RetryBackoff,
+        salvage_partial: bool,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        let timeout = timeout.unwrap_or(Duration::MAX);
+        let deadline = backoff
+            .deadline
+            .and_then(|d| tokio::time::Instant::now().checked_add(d));
+        let (attempts, budget) = match policy {
+            RetryPolicy::Attempts(attempts) => (attempts, None),
+            RetryPolicy::Cost {
+                max_extra_usd,
+                max_attempts,
+            } => (max_attempts, Some(max_extra_usd)),
         };
 
+        let mut extra_usd = 0.0;
         let mut last = None;
-        for idx in 0..retry {
-            match tokio::time::timeout(timeout, self.complete(req.clone(), prefix)).await {
-                Ok(r) => {
-                    last = Some(r);
+        let mut salvage = false;
+        // The same key is sent on every attempt so the gateway can dedupe retries.
+        let mut headers = HeaderMap::new();
+        if let Some(name) = self.idempotency_header.as_ref() {
+            let key = new_idempotency_key();
+            debug!("Idempotency key {} for {:?}", &key, prefix);
+            headers.insert(
+                name.clone(),
+                HeaderValue::from_str(&key).map_err(|e| PromptError::Other(eyre!(e)))?,
+            );
+        }
+        let mut retry_hint = None;
+        for idx in 0..attempts {
+            Span::current().record("attempts", idx + 1);
+            if idx > 0 {
+                // A rate limit error tells how long to wait, otherwise back off.
+                let delay = retry_hint.take().unwrap_or_else(|| backoff.delay(idx));
+                if deadline.is_some_and(|d| tokio::time::Instant::now() + delay >= d) {
+                    warn!(
+                        "Stop retrying at {} retry, the retry deadline is reached",
+                        idx
+                    );
+                    break;
                 }
-                Err(_) => {
-                    warn!("Timeout with {} retry, timeout = {:?}", idx, timeout);
-                    continue;
+                debug!("Retry {} after {:?}", idx, delay);
+                #[cfg(feature = "metrics")]
+                crate::metrics::record_retry(&self.model.to_string(), prefix.unwrap_or("llm"));
+                tokio::time::sleep(delay).await;
+            }
+            // The attempt timeout never goes past the overall deadline.
+            let timeout = match deadline {
+                Some(d) => timeout.min(d.saturating_duration_since(tokio::time::Instant::now())),
+                None => timeout,
+            };
+            let estimate = self.estimate_input_cost(req);
+            if idx > 0
+                && let Some(budget) = budget
+                && extra_usd + estimate > budget
+            {
+                warn!(
+                    "Stop retrying at {} retry, extra cost {:.4} + estimated {:.4} exceeds retry budget {}",
+                    idx, extra_usd, estimate, budget
+                );
+                break;
+            }
+
+            // Outside of the attempt timeout, waiting here is not a failure.
+            self.throttle_spend().await;
+            let before = self.billing.read().await.current;
+            let mut opts = SendOptions {
+                deadline: None,
+                headers: headers.clone(),
+            };
+            let result = match tokio::time::Instant::now().checked_add(timeout) {
+                // Some grace for the connection and bookkeeping after the stream is cut.
+                Some(deadline) if salvage => {
+                    opts.deadline = Some(deadline);
+                    tokio::time::timeout(
+                        timeout.saturating_add(Duration::from_secs(5)),
+                        self.complete_until(req.clone(), prefix, &opts),
+                    )
+                    .await
+                }
+                _ => {
+                    tokio::time::timeout(timeout, self.complete_until(req.clone(), prefix, &opts))
+                        .await
                 }
             };
+            if idx > 0 {
+                // Failed attempts are usually not billed by us but likely still charged input.
+                let billed = self.billing.read().await.current - before;
+                extra_usd += if billed > 0.0 { billed } else { estimate };
+            }
 
-            match last {
-                Some(Ok(r)) => return Ok(r),
-                Some(Err(ref e)) => {
+            match result {
+                Ok(Ok(r)) => return Ok(r),
+                Ok(Err(e)) => {
                     warn!(
                         "Having an error {} during {} retry (timeout is {:?})",
                         e, idx, timeout
                     );
+                    if let Some(info) = RateLimitInfo::from_error(&e) {
+                        retry_hint = info.retry_after;
+                        *self.rate_limit.write().await = info;
+                    }
+                    last = Some(Err(e));
+                }
+                Err(_) => {
+                    warn!("Timeout with {} retry, timeout = {:?}", idx, timeout);
+                    salvage = salvage_partial;
                 }
-                _ => {}
             }
         }
 
@@ -769,7 +2375,47 @@ impl LLMInner {
         req: CreateChatCompletionRequest,
         prefix: Option<&str>,
     ) -> Result<CreateChatCompletionResponse, PromptError> {
-        let use_stream = self.default_settings.llm_stream;
+        self.throttle_spend().await;
+        self.complete_until(req, prefix, &SendOptions::default())
+            .await
+    }
+
+    // With a deadline, the request is streamed and cut at the deadline, keeping the
+    // partial output.
+    #[tracing::instrument(
+        name = "llm.completion",
+        skip_all,
+        fields(
+            model = %self.model,
+            prefix = prefix.unwrap_or("llm"),
+            input_tokens = Empty,
+            cached_tokens = Empty,
+            output_tokens = Empty,
+            reasoning_tokens = Empty,
+            cost = Empty,
+            finish_reason = Empty,
+            tool_calls = Empty,
+        )
+    )]
+    async fn complete_until(
+        &self,
+        mut req: CreateChatCompletionRequest,
+        prefix: Option<&str>,
+        opts: &SendOptions,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        let redactions = self
+            .redactor
+            .as_ref()
+            .map(|redactor| redactor.redact_request(&mut req));
+        if let Some(memory) = self.memory.as_ref() {
+            inject_memory(&mut req, memory)?;
+        }
+        if self.default_settings.llm_auto_max_tokens {
+            self.auto_max_tokens(&mut req);
+        }
+        self.provider.adjust_request(&mut req);
+        self.role_mapping.apply(&mut req);
+        let use_stream = self.default_settings.llm_stream || opts.deadline.is_some();
         let prefix = if let Some(prefix) = prefix {
             prefix.to_string()
         } else {
@@ -777,210 +2423,622 @@ impl LLMInner {
         };
         let debug_fp = self.on_llm_debug(&prefix);
 
-        if let Some(debug_fp) = debug_fp.as_ref() {
-            if let Err(e) = Self::save_llm_user(debug_fp, &req).await {
-                warn!("Fail to save user due to {}", e);
-            }
+        if let Some((_, debug_fp)) = debug_fp.as_ref()
+            && let Err(e) =
+                Self::save_llm_user(debug_fp, &req, &opts.headers, &self.dump_options).await
+        {
+            warn!("Fail to save user due to {}", e);
         }
 
         trace!(
             "Sending completion request: {:?}",
             &serde_json::to_string(&req)
         );
-        let resp = if use_stream {
-            self.complete_streaming(req).await?
+        let mut dump_stats = debug_fp.as_ref().map(|(index, _)| {
+            DumpStats::request(*index, &prefix, &req, self.token_counter.as_ref())
+        });
+        let tool_tokens = self
+            .token_counter
+            .count_tools(req.tools.as_deref().unwrap_or_default());
+        // A stream cut at the deadline never gets its usage, the partial output is
+        // still charged by the provider.
+        let usage_fallback = if opts.deadline.is_some() {
+            UsageFallback::Estimate
         } else {
-            self.client.create_chat(req).await?
+            self.usage_fallback
         };
-
-        if let Some(debug_fp) = debug_fp.as_ref() {
-            if let Err(e) = Self::save_llm_resp(debug_fp, &resp).await {
-                warn!("Fail to save resp due to {}", e);
+        let estimated_input = (usage_fallback == UsageFallback::Estimate)
+            .then(|| self.token_counter.count_messages(&req.messages));
+        let mut genai = (self.genai_log.is_some() || cfg!(feature = "otel"))
+            .then(|| GenAIRecord::request(&req, self.provider, &prefix));
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let sent = self.send(req, use_stream, opts, &prefix).await;
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_request(
+            &self.model.to_string(),
+            &prefix,
+            start.elapsed(),
+            if sent.is_ok() { "ok" } else { "error" },
+        );
+        let mut resp = match sent {
+            Ok(resp) => resp,
+            Err(e) => {
+                if let Some((_, debug_fp)) = debug_fp.as_ref()
+                    && let Err(e) = Self::save_llm_error(debug_fp, &e).await
+                {
+                    warn!("Fail to save error due to {}", e);
+                }
+                if let Some(record) = genai.as_mut() {
+                    record.fail(&e);
+                    self.write_genai(record).await;
+                }
+                return Err(e);
             }
+        };
+
+        if let Some((_, debug_fp)) = debug_fp.as_ref()
+            && let Err(e) = Self::save_llm_resp(debug_fp, &resp, &self.dump_options).await
+        {
+            warn!("Fail to save resp due to {}", e);
         }
 
-        if let Some(usage) = &resp.usage {
-            let cached = usage
-                .prompt_tokens_details
-                .as_ref()
-                .map(|v| v.cached_tokens)
-                .flatten()
-                .unwrap_or_default();
-            let input = usage.prompt_tokens - cached;
-            self.billing
-                .write()
-                .await
-                .input_tokens(&self.model, input as _, cached as _)
-                .map_err(PromptError::Other)?;
-            self.billing
-                .write()
-                .await
-                .output_tokens(
-                    &self.model,
-                    usage.completion_tokens as u64,
-                    usage
-                        .completion_tokens_details
-                        .as_ref()
-                        .map(|v| v.reasoning_tokens)
-                        .flatten()
-                        .unwrap_or_default() as u64,
-                )
-                .map_err(PromptError::Other)?;
+        let usage = self.usage_or_fallback(&resp, usage_fallback, estimated_input)?;
+        let (cost, billed) = if let Some(usage) = &usage {
+            let tag = billing_tag();
+            self.bill_usage(
+                &prefix,
+                tag,
+                usage,
+                tool_tokens,
+                Some(ResponseMeta::from(&resp)),
+            )
+            .await
         } else {
-            warn!("No usage?!")
+            warn!("No usage?!");
+            (0.0, Ok(()))
+        };
+        record_completion_span(&resp, usage.as_ref(), cost);
+
+        if let Some((index, debug_fp)) = debug_fp.as_ref() {
+            self.index_dump(*index, debug_fp, &prefix, billing_tag(), &resp, cost)
+                .await;
+            if let Some(stats) = dump_stats.as_mut() {
+                stats.response(&resp, usage.as_ref(), self.token_counter.as_ref());
+                if let Err(e) =
+                    append_jsonl(&debug_fp.with_file_name(DEBUG_STATS_FILE), stats).await
+                {
+                    warn!("Fail to update debug stats due to {}", e);
+                }
+            }
+        }
+        if let Some(record) = genai.as_mut() {
+            record.finish(&resp, usage.as_ref(), cost);
+            self.write_genai(record).await;
+        }
+        billed.map_err(PromptError::Other)?;
+        debug!("Response id {} by {}", &resp.id, &resp.model);
+
+        if let Some(redactions) = redactions.as_ref() {
+            redactions.restore_response(&mut resp);
         }
 
+        self.record_finish_reasons(&prefix, &resp).await;
+
         info!("Model Billing: {}", &self.billing.read().await);
         Ok(resp)
     }
 
-    async fn complete_streaming(
+    // The usage of `resp`, or what `fallback` makes of its absence.
+    fn usage_or_fallback(
+        &self,
+        resp: &CreateChatCompletionResponse,
+        fallback: UsageFallback,
+        estimated_input: Option<usize>,
+    ) -> Result<Option<CompletionUsage>, PromptError> {
+        Ok(match (resp.usage.clone(), fallback) {
+            (Some(usage), _) => Some(usage),
+            (None, UsageFallback::Zero) => None,
+            (None, UsageFallback::Error) => {
+                return Err(PromptError::Other(eyre!(
+                    "no usage in response {} from {}",
+                    &resp.id,
+                    &resp.model
+                )));
+            }
+            (None, UsageFallback::Estimate) => {
+                let prompt_tokens = estimated_input.unwrap_or_default() as u32;
+                let completion_tokens = resp
+                    .choices
+                    .iter()
+                    .map(|c| self.token_counter.count(&response_to_string(&c.message)))
+                    .sum::<usize>() as u32;
+                warn!(
+                    "No usage in response, billing estimated {} input and {} output tokens",
+                    prompt_tokens, completion_tokens
+                );
+                Some(CompletionUsage {
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens: prompt_tokens + completion_tokens,
+                    prompt_tokens_details: None,
+                    completion_tokens_details: None,
+                })
+            }
+        })
+    }
+
+    async fn index_dump(
+        &self,
+        index: u64,
+        debug_fp: &Path,
+        prefix: &str,
+        tag: Option<String>,
+        resp: &CreateChatCompletionResponse,
+        cost: f64,
+    ) {
+        let entry = DumpIndexEntry {
+            index,
+            file: debug_fp
+                .file_name()
+                .map(|f| f.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            time: Utc::now(),
+            prefix: prefix.to_string(),
+            tag,
+            model: self.model.to_string(),
+            response_id: resp.id.clone(),
+            cost,
+        };
+        if let Err(e) = entry.append_to(debug_fp).await {
+            warn!("Fail to update debug index due to {}", e);
+        }
+    }
+
+    // Charge `usage` and add it to the ledger, returns the cost and whether the caps
+    // still hold.
+    async fn bill_usage(
+        &self,
+        prefix: &str,
+        tag: Option<String>,
+        usage: &CompletionUsage,
+        tool_tokens: usize,
+        response: Option<ResponseMeta>,
+    ) -> (f64, Result<()>) {
+        let tokens = UsageTokens::from(usage);
+        let mut billing = self.billing.write().await;
+        let (cost, billed) = billing.usage(&self.model, usage);
+        let entry = BillingEntry {
+            time: Utc::now(),
+            model: self.model.to_string(),
+            prefix: prefix.to_string(),
+            tag,
+            input_tokens: tokens.input,
+            cached_tokens: tokens.cached,
+            output_tokens: tokens.output + tokens.reasoning,
+            reasoning_tokens: tokens.reasoning,
+            // The estimate never goes past what was actually billed.
+            tool_tokens: tool_tokens.min(usage.prompt_tokens as usize) as _,
+            cost,
+            response,
+        };
+        self.record_billing(billing, entry).await;
+        (cost, billed)
+    }
+
+    // Push the entry to the ledger, then persist and notify the hooks out of the lock.
+    pub(crate) async fn record_billing(
+        &self,
+        mut billing: RwLockWriteGuard<'_, ModelBilling>,
+        entry: BillingEntry,
+    ) {
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_billing(&entry);
+        if let Some(limiter) = self.spend_limiter.as_ref() {
+            limiter.charge(entry.cost);
+        }
+        billing.add_to_ledger(&entry);
+        let event = BillingEvent {
+            entry,
+            current: billing.current,
+            cap: billing.cap,
+            in_cap: billing.in_cap(),
+        };
+        drop(billing);
+        self.persist_billing().await;
+        if let Some(path) = self.billing_ledger.as_ref()
+            && let Err(e) = append_jsonl(path, &event.entry).await
+        {
+            warn!("Fail to append billing ledger due to {}", e);
+        }
+        self.billing_hooks.notify(&event);
+    }
+
+    /// Call `hook` after every billed request, e.g. to forward costs to metrics.
+    pub fn on_billing<F: Fn(&BillingEvent) + Send + Sync + 'static>(&self, hook: F) {
+        self.billing_hooks.push(Arc::new(hook));
+    }
+
+    pub(crate) async fn persist_billing(&self) {
+        if let Some(path) = self.billing_state.as_ref() {
+            // Saves take turns on the temporary file, each writing a snapshot at
+            // least as recent as the previous one. Billing is only read locked for
+            // the copy, so completions never wait on the disk.
+            let _saving = self.billing_save.lock().await;
+            let billing = self.billing.read().await.clone();
+            if let Err(e) = billing.save(path).await {
+                warn!("Fail to save billing state due to {}", e);
+            }
+        }
+    }
+
+    /// Stream completion chunks as they arrive, e.g. to show partial output. Usage is
+    /// billed once the stream is exhausted or fails. Redacted values are restored in
+    /// the content deltas, a placeholder split across chunks comes out once complete.
+    pub async fn complete_stream(
+        &self,
+        req: CreateChatCompletionRequest,
+        prefix: Option<&str>,
+    ) -> Result<BoxStream<'_, Result<CreateChatCompletionStreamResponse, PromptError>>, PromptError>
+    {
+        let (stream, redactions) = self.stream_chunks(req, prefix).await?;
+        let Some(redactions) = redactions else {
+            return Ok(stream);
+        };
+        let mut restorer = DeltaRestorer::new(redactions);
+        Ok(stream
+            .map(move |chunk| {
+                chunk.map(|mut chunk| {
+                    restorer.restore_chunk(&mut chunk);
+                    chunk
+                })
+            })
+            .boxed())
+    }
+
+    #[tracing::instrument(
+        name = "llm.stream",
+        skip_all,
+        fields(model = %self.model, prefix = prefix.unwrap_or("llm"))
+    )]
+    pub(crate) async fn stream_chunks(
         &self,
         mut req: CreateChatCompletionRequest,
-    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        prefix: Option<&str>,
+    ) -> Result<ChunkStream<'_>, PromptError> {
+        self.throttle_spend().await;
+        let redactions = self
+            .redactor
+            .as_ref()
+            .map(|redactor| redactor.redact_request(&mut req));
+        if let Some(memory) = self.memory.as_ref() {
+            inject_memory(&mut req, memory)?;
+        }
+        if self.default_settings.llm_auto_max_tokens {
+            self.auto_max_tokens(&mut req);
+        }
+        self.provider.adjust_request(&mut req);
+        self.role_mapping.apply(&mut req);
         if req.stream_options.is_none() {
             req.stream_options = Some(ChatCompletionStreamOptions {
                 include_usage: Some(true),
                 include_obfuscation: None,
             });
         }
-
-        let mut stream = self.client.create_chat_stream(req).await?;
-
-        let mut id: Option<String> = None;
-        let mut created: Option<u32> = None;
-        let mut model: Option<String> = None;
-        let mut service_tier = None;
-        let mut system_fingerprint = None;
-        let mut usage: Option<CompletionUsage> = None;
-
-        let mut contents: Vec<String> = Vec::new();
-        let mut finish_reasons: Vec<Option<FinishReason>> = Vec::new();
-        let mut tool_calls: Vec<Vec<ToolCallAcc>> = Vec::new();
-
-        while let Some(item) = stream.next().await {
-            let chunk: CreateChatCompletionStreamResponse = item?;
-            if id.is_none() {
-                id = Some(chunk.id.clone());
-            }
-            created = Some(chunk.created);
-            model = Some(chunk.model.clone());
-            service_tier = chunk.service_tier.clone();
-            system_fingerprint = chunk.system_fingerprint.clone();
-            if let Some(u) = chunk.usage.clone() {
-                usage = Some(u);
+        let prefix = prefix.unwrap_or("llm").to_string();
+        let debug_fp = self.on_llm_debug(&prefix);
+        if let Some((_, debug_fp)) = debug_fp.as_ref()
+            && let Err(e) =
+                Self::save_llm_user(debug_fp, &req, &HeaderMap::new(), &self.dump_options).await
+        {
+            warn!("Fail to save user due to {}", e);
+        }
+        // The stream is polled outside of the caller's task local scope.
+        let tag = billing_tag();
+        let tool_tokens = self
+            .token_counter
+            .count_tools(req.tools.as_deref().unwrap_or_default());
+        let estimated_input = self.token_counter.count_messages(&req.messages);
+        let model = req.model.clone();
+        let inner = match self
+            .client
+            .create_chat_stream_with_headers(req, HeaderMap::new())
+            .await
+        {
+            Ok(inner) => inner,
+            Err(e) => {
+                if let Some((_, debug_fp)) = debug_fp.as_ref()
+                    && let Err(e) = Self::save_llm_error(debug_fp, &e).await
+                {
+                    warn!("Fail to save error due to {}", e);
+                }
+                return Err(e);
             }
+        };
 
-            for ch in chunk.choices.into_iter() {
-                let idx = ch.index as usize;
-                if contents.len() <= idx {
-                    contents.resize_with(idx + 1, String::new);
-                    finish_reasons.resize_with(idx + 1, || None);
-                    tool_calls.resize_with(idx + 1, Vec::new);
-                }
-                if let Some(delta) = ch.delta.content {
-                    contents[idx].push_str(&delta);
+        let finish = StreamFinish {
+            prefix,
+            tag,
+            tool_tokens,
+            estimated_input,
+            model,
+            debug_fp,
+        };
+        let state = (inner, StreamAcc::default(), Some(finish));
+        let stream = stream::unfold(state, move |(mut inner, mut acc, finish)| async move {
+            let finish = finish?;
+            match inner.next().await {
+                Some(Ok(chunk)) => {
+                    acc.push(chunk.clone());
+                    Some((Ok(chunk), (inner, acc, Some(finish))))
                 }
-                if let Some(tcs) = ch.delta.tool_calls {
-                    for tc in tcs.into_iter() {
-                        let tc_idx = tc.index as usize;
-                        if tool_calls[idx].len() <= tc_idx {
-                            tool_calls[idx].resize_with(tc_idx + 1, ToolCallAcc::default);
-                        }
-                        let acc = &mut tool_calls[idx][tc_idx];
-                        if let Some(id) = tc.id {
-                            acc.id = id;
-                        }
-                        if let Some(func) = tc.function {
-                            if let Some(name) = func.name {
-                                acc.name = name;
-                            }
-                            if let Some(args) = func.arguments {
-                                acc.arguments.push_str(&args);
-                            }
-                        }
+                Some(Err(e)) => {
+                    let e = PromptError::from_provider(e);
+                    if let Err(billed) = self.finish_stream(acc, finish, Some(&e)).await {
+                        warn!("Fail to bill the failed stream due to {}", billed);
                     }
+                    Some((Err(e), (inner, StreamAcc::default(), None)))
                 }
-                if ch.finish_reason.is_some() {
-                    finish_reasons[idx] = ch.finish_reason;
+                None => self
+                    .finish_stream(acc, finish, None)
+                    .await
+                    .err()
+                    .map(|e| (Err(e), (inner, StreamAcc::default(), None))),
+            }
+        });
+        Ok((stream.boxed(), redactions))
+    }
+
+    // Dump and bill a stream once it ended or failed. A failed stream is billed for
+    // what arrived, estimated if the usage never came.
+    async fn finish_stream(
+        &self,
+        acc: StreamAcc,
+        finish: StreamFinish,
+        error: Option<&PromptError>,
+    ) -> Result<(), PromptError> {
+        let started = acc.id.is_some();
+        let resp = acc.finish(error.is_some(), &finish.model);
+        if let Some((_, debug_fp)) = finish.debug_fp.as_ref() {
+            if let Err(e) = Self::save_llm_resp(debug_fp, &resp, &self.dump_options).await {
+                warn!("Fail to save resp due to {}", e);
+            }
+            if let Some(error) = error
+                && let Err(e) = Self::save_llm_error(debug_fp, error).await
+            {
+                warn!("Fail to save error due to {}", e);
+            }
+        }
+
+        let fallback = match error {
+            // Nothing was generated, likely nothing charged either.
+            Some(_) if !started => UsageFallback::Zero,
+            Some(_) => UsageFallback::Estimate,
+            None => self.usage_fallback,
+        };
+        let usage = self.usage_or_fallback(&resp, fallback, Some(finish.estimated_input))?;
+        let (cost, billed) = match usage.as_ref() {
+            Some(usage) => {
+                self.bill_usage(
+                    &finish.prefix,
+                    finish.tag.clone(),
+                    usage,
+                    finish.tool_tokens,
+                    Some(ResponseMeta::from(&resp)),
+                )
+                .await
+            }
+            None => {
+                if started {
+                    warn!("No usage?!");
                 }
+                (0.0, Ok(()))
             }
+        };
+        if let Some((index, debug_fp)) = finish.debug_fp.as_ref() {
+            self.index_dump(*index, debug_fp, &finish.prefix, finish.tag, &resp, cost)
+                .await;
         }
+        info!("Model Billing: {}", &self.billing.read().await);
+        billed.map_err(PromptError::Other)
+    }
 
-        let mut choices = Vec::new();
-        for (idx, content) in contents.into_iter().enumerate() {
-            let finish_reason = finish_reasons.get(idx).cloned().unwrap_or(None);
-            let built_tool_calls = tool_calls
-                .get(idx)
-                .cloned()
-                .unwrap_or_default()
-                .into_iter()
-                .filter(|t| !t.name.trim().is_empty() || !t.arguments.trim().is_empty())
-                .map(|t| {
-                    ChatCompletionMessageToolCalls::Function(ChatCompletionMessageToolCall {
-                        id: if t.id.trim().is_empty() {
-                            format!("toolcall-{}", idx)
-                        } else {
-                            t.id
-                        },
-                        function: FunctionCall {
-                            name: t.name,
-                            arguments: t.arguments,
-                        },
-                    })
-                })
-                .collect::<Vec<_>>();
-            let tool_calls_opt = if built_tool_calls.is_empty() {
-                None
+    // Warn once a prefix has enough samples and too many truncated responses.
+    async fn record_finish_reasons(&self, prefix: &str, resp: &CreateChatCompletionResponse) {
+        const MIN_SAMPLES: u64 = 10;
+
+        let mut stats = self.stats.write().await;
+        let reasons = stats.finish_reasons_of(&self.model.to_string(), prefix);
+        for choice in resp.choices.iter() {
+            reasons.record(choice.finish_reason.as_ref());
+        }
+        if reasons.total() >= MIN_SAMPLES && reasons.length_ratio() > self.length_warn_ratio {
+            warn!(
+                "{:.1}% responses of {} with prefix {} hit the length limit, consider raising max_completion_tokens",
+                reasons.length_ratio() * 100.0,
+                &self.model,
+                prefix
+            );
+        }
+    }
+
+    async fn write_genai(&self, record: &GenAIRecord) {
+        if let Some(path) = self.genai_log.as_ref()
+            && let Err(e) = record.append_to(path).await
+        {
+            warn!("Fail to write GenAI log due to {}", e);
+        }
+        #[cfg(feature = "otel")]
+        record.emit_span();
+    }
+
+    /// Rate limit state from the last rate limit error.
+    pub async fn rate_limit(&self) -> RateLimitInfo {
+        self.rate_limit.read().await.clone()
+    }
+
+    pub async fn stats(&self) -> LLMStats {
+        self.stats.read().await.clone()
+    }
+
+    async fn send_once(
+        &self,
+        client: &LLMClient,
+        req: CreateChatCompletionRequest,
+        use_stream: bool,
+        opts: &SendOptions,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        let exchange = async {
+            if use_stream {
+                self.complete_streaming(client, req, opts).await
             } else {
-                Some(built_tool_calls)
-            };
-            choices.push(ChatChoice {
-                index: idx as u32,
-                message: ChatCompletionResponseMessage {
-                    content: if content.is_empty() {
-                        None
-                    } else {
-                        Some(content)
-                    },
-                    refusal: None,
-                    tool_calls: tool_calls_opt,
-                    annotations: None,
-                    role: Role::Assistant,
-                    function_call: None,
-                    audio: None,
-                },
-                finish_reason,
-                logprobs: None,
+                client
+                    .create_chat_with_headers(req, opts.headers.clone())
+                    .await
+            }
+        };
+        let timeout = HTTP_TIMEOUT.try_with(|t| *t).ok().or(self
+            .default_settings
+            .llm_http_timeout
+            .map(Duration::from_secs));
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, exchange)
+                .await
+                .map_err(|_| PromptError::Other(eyre!("http timeout after {:?}", timeout)))?,
+            None => exchange.await,
+        }
+    }
+
+    // With hedging, the same request is fired again after the latency threshold and
+    // the first success wins. The other one is dropped, but the provider likely
+    // charged its prompt already, so the estimated prompt is billed for it. Output it
+    // generated before being dropped is never known and not billed.
+    async fn send(
+        &self,
+        req: CreateChatCompletionRequest,
+        use_stream: bool,
+        opts: &SendOptions,
+        prefix: &str,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        let Some(hedge) = self.hedge.as_ref() else {
+            return self.send_once(&self.client, req, use_stream, opts).await;
+        };
+
+        let primary = self.send_once(&self.client, req.clone(), use_stream, opts);
+        tokio::pin!(primary);
+        tokio::select! {
+            resp = &mut primary => return resp,
+            _ = tokio::time::sleep(hedge.after) => {}
+        }
+
+        debug!(
+            "No response after {:?}, sending hedged request",
+            hedge.after
+        );
+        let tool_tokens = self
+            .token_counter
+            .count_tools(req.tools.as_deref().unwrap_or_default());
+        let prompt_tokens = self.token_counter.count_messages(&req.messages) + tool_tokens;
+        // A different request for the gateway, which may dedupe or reject it otherwise.
+        let mut hedge_opts = opts.clone();
+        if let Some(name) = self.idempotency_header.as_ref()
+            && hedge_opts.headers.contains_key(name)
+        {
+            let key = new_idempotency_key();
+            debug!("Idempotency key {} for the hedged request", &key);
+            hedge_opts.headers.insert(
+                name.clone(),
+                HeaderValue::from_str(&key).map_err(|e| PromptError::Other(eyre!(e)))?,
+            );
+        }
+        let client = hedge.client.as_ref().unwrap_or(&self.client);
+        let secondary = self.send_once(client, req, use_stream, &hedge_opts);
+        tokio::pin!(secondary);
+        let (resp, cancelled) = tokio::select! {
+            resp = &mut primary => match resp {
+                Ok(resp) => (Ok(resp), true),
+                Err(e) => {
+                    warn!("Primary request failed with {}, waiting for the hedged one", e);
+                    (secondary.await, false)
+                }
+            },
+            resp = &mut secondary => match resp {
+                Ok(resp) => (Ok(resp), true),
+                Err(e) => {
+                    warn!("Hedged request failed with {}, waiting for the primary one", e);
+                    (primary.await, false)
+                }
+            },
+        };
+        if cancelled {
+            self.bill_cancelled(prefix, prompt_tokens, tool_tokens)
+                .await;
+        }
+        resp
+    }
+
+    // Bill the prompt of a request dropped before its usage came back.
+    async fn bill_cancelled(&self, prefix: &str, prompt_tokens: usize, tool_tokens: usize) {
+        let usage = CompletionUsage {
+            prompt_tokens: prompt_tokens as u32,
+            completion_tokens: 0,
+            total_tokens: prompt_tokens as u32,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        };
+        let (cost, billed) = self
+            .bill_usage(prefix, billing_tag(), &usage, tool_tokens, None)
+            .await;
+        debug!(
+            "Billed estimated {} prompt tokens ({:.4} USD) of the cancelled request",
+            prompt_tokens, cost
+        );
+        if let Err(e) = billed {
+            warn!("Cap reached by the cancelled request: {}", e);
+        }
+    }
+
+    #[allow(deprecated)]
+    async fn complete_streaming(
+        &self,
+        client: &LLMClient,
+        mut req: CreateChatCompletionRequest,
+        opts: &SendOptions,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        if req.stream_options.is_none() {
+            req.stream_options = Some(ChatCompletionStreamOptions {
+                include_usage: Some(true),
+                include_obfuscation: None,
             });
         }
-        if choices.is_empty() {
-            choices.push(ChatChoice {
-                index: 0,
-                message: ChatCompletionResponseMessage {
-                    content: Some(String::new()),
-                    refusal: None,
-                    tool_calls: None,
-                    annotations: None,
-                    role: Role::Assistant,
-                    function_call: None,
-                    audio: None,
+
+        let mut stream = client
+            .create_chat_stream_with_headers(req, opts.headers.clone())
+            .await?;
+
+        let mut acc = StreamAcc::default();
+        let mut truncated = false;
+        loop {
+            let item = match opts.deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, stream.next()).await {
+                    Ok(item) => item,
+                    Err(_) => {
+                        truncated = true;
+                        break;
+                    }
                 },
-                finish_reason: None,
-                logprobs: None,
-            });
+                None => stream.next().await,
+            };
+            let Some(item) = item else {
+                break;
+            };
+            acc.push(item.map_err(PromptError::from_provider)?);
         }
 
-        Ok(CreateChatCompletionResponse {
-            id: id.unwrap_or_else(|| "stream".to_string()),
-            choices,
-            created: created.unwrap_or(0),
-            model: model.unwrap_or_else(|| self.model.to_string()),
-            service_tier,
-            system_fingerprint,
-            object: "chat.completion".to_string(),
-            usage,
-        })
+        if truncated {
+            warn!("Stream cut at the deadline, salvaging the partial response");
+        }
+        Ok(acc.finish(truncated, &self.model.to_string()))
     }
 
     pub async fn prompt_once(
@@ -998,26 +3056,199 @@ impl LLMInner {
         let user = ChatCompletionRequestUserMessageArgs::default()
             .content(user_msg)
             .build()?;
-        let mut req = CreateChatCompletionRequestArgs::default();
+        let req = self
+            .request_args(&settings, prefix)
+            .messages(vec![sys.into(), user.into()])
+            .build()?;
+        self.complete(req, prefix).await
+    }
+}
 
-        if let Some(tc) = settings.llm_tool_choice {
-            req.tool_choice(tc);
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn test_period_start() {
+        let now = at(2025, 3, 15, 13, 42) + Duration::from_secs(7);
+        assert_eq!(QuotaPeriod::Hour.start_of(now), at(2025, 3, 15, 13, 0));
+        assert_eq!(QuotaPeriod::Day.start_of(now), at(2025, 3, 15, 0, 0));
+        assert_eq!(QuotaPeriod::Month.start_of(now), at(2025, 3, 1, 0, 0));
+    }
+
+    #[test]
+    fn test_window_roll() {
+        let mut window = QuotaWindow::new(QuotaPeriod::Hour, 1.0);
+        window.start = at(2025, 3, 15, 13, 0);
+        window.current = 0.8;
+        window.roll(at(2025, 3, 15, 13, 59));
+        assert_eq!(window.current, 0.8);
+        window.roll(at(2025, 3, 15, 14, 0));
+        assert_eq!(window.current, 0.0);
+        assert_eq!(window.start, at(2025, 3, 15, 14, 0));
+    }
+
+    #[test]
+    fn test_window_cap() {
+        let mut billing = ModelBilling::new(10.0).with_window(QuotaPeriod::Day, 1.0);
+        billing.charge(0.6);
+        assert!(billing.in_cap());
+        billing.charge(0.6);
+        assert!(!billing.in_cap());
+        assert_eq!(billing.current, 1.2);
+
+        // A window of a past period no longer blocks, it resets on the next charge.
+        billing.windows[0].start -= chrono::Duration::days(1);
+        assert!(billing.in_cap());
+        billing.charge(0.1);
+        assert!((billing.windows[0].current - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_restore_keeps_caps() {
+        let mut saved = ModelBilling::new(1.0).with_window(QuotaPeriod::Hour, 0.5);
+        saved.charge(0.3);
+        let mut billing = ModelBilling::new(5.0)
+            .with_window(QuotaPeriod::Hour, 2.0)
+            .with_window(QuotaPeriod::Day, 3.0);
+        billing.restore(saved);
+        assert_eq!(billing.cap, 5.0);
+        assert_eq!(billing.current, 0.3);
+        assert_eq!(billing.windows[0].cap, 2.0);
+        assert_eq!(billing.windows[0].current, 0.3);
+        assert_eq!(billing.windows[1].current, 0.0);
+    }
+
+    fn entry(input_tokens: u64, cached_tokens: u64, tool_tokens: u64) -> BillingEntry {
+        BillingEntry {
+            time: Utc::now(),
+            model: "gpt-4o".to_string(),
+            prefix: "llm".to_string(),
+            tag: None,
+            input_tokens,
+            cached_tokens,
+            output_tokens: 0,
+            reasoning_tokens: 0,
+            tool_tokens,
+            cost: 0.5,
+            response: None,
         }
+    }
 
-        if let Some(effort) = settings.reasoning_effort {
-            req.reasoning_effort(effort.0);
+    #[test]
+    fn test_tool_token_share() {
+        let mut billing = ModelBilling::new(1.0);
+        assert_eq!(billing.tool_token_share(), 0.0);
+        billing.add_to_ledger(&entry(60, 40, 50));
+        billing.add_to_ledger(&entry(100, 0, 0));
+        assert_eq!(billing.tool_token_share(), 0.25);
+    }
+
+    #[test]
+    fn test_ledger_rollup() {
+        let mut billing = ModelBilling::new(1.0);
+        let mut tagged = entry(10, 0, 0);
+        tagged.tag = Some("team".to_string());
+        for _ in 0..1000 {
+            billing.add_to_ledger(&entry(10, 0, 0));
+            billing.add_to_ledger(&tagged);
         }
+        assert_eq!(billing.ledger.len(), 2);
+        assert_eq!(billing.ledger[0].requests, 1000);
+        assert_eq!(billing.ledger[0].input_tokens, 10000);
+        assert_eq!(billing.cost_by_tag()["team"], 500.0);
+        assert_eq!(billing.cost_by_tag()[""], 500.0);
+    }
 
-        if let Some(prefix) = prefix.as_ref() {
-            req.prompt_cache_key(prefix.to_string());
+    #[test]
+    fn test_restore_legacy_ledger() {
+        let saved = serde_json::json!({
+            "current": 1.0,
+            "cap": 10.0,
+            "ledger": [
+                serde_json::to_value(entry(10, 0, 0)).unwrap(),
+                serde_json::to_value(entry(20, 5, 0)).unwrap(),
+            ],
+        });
+        let mut billing = ModelBilling::new(10.0);
+        billing.restore(serde_json::from_value(saved).unwrap());
+        assert_eq!(billing.ledger.len(), 1);
+        assert_eq!(billing.ledger[0].requests, 2);
+        assert_eq!(billing.ledger[0].input_tokens, 30);
+        assert_eq!(billing.ledger[0].cost, 1.0);
+    }
+
+    #[test]
+    fn test_usage_bills_reasoning_once() {
+        let usage: CompletionUsage = serde_json::from_value(serde_json::json!({
+            "prompt_tokens": 1_000_000,
+            "completion_tokens": 1_000_000,
+            "total_tokens": 2_000_000,
+            "prompt_tokens_details": {"cached_tokens": 400_000},
+            "completion_tokens_details": {"reasoning_tokens": 600_000},
+        }))
+        .unwrap();
+        let tokens = UsageTokens::from(&usage);
+        assert_eq!(
+            tokens,
+            UsageTokens {
+                input: 600_000,
+                cached: 400_000,
+                output: 400_000,
+                reasoning: 600_000,
+            }
+        );
+
+        // gpt-4o: 2.5 input, 1.25 cached, 10 output per 1M tokens.
+        let mut billing = ModelBilling::new(100.0);
+        let (cost, billed) = billing.usage(&OpenAIModel::GPT4O, &usage);
+        assert!(billed.is_ok());
+        assert!((cost - (1.5 + 0.5 + 10.0)).abs() < 1e-9);
+        assert_eq!(billing.reasoning_tokens, 600_000);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = RetryBackoff {
+            base: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+            deadline: None,
+        };
+        for (retry, exp) in [(1, 100), (2, 200), (4, 800), (10, 1000)] {
+            let exp = Duration::from_millis(exp);
+            let delays: Vec<_> = (0..16).map(|_| backoff.delay(retry)).collect();
+            assert!(delays.iter().all(|d| *d >= exp / 2 && *d <= exp));
+            assert!(delays.iter().any(|d| *d != delays[0]));
         }
-        let req = req
-            .messages(vec![sys.into(), user.into()])
-            .model(self.model.to_string())
-            .temperature(settings.llm_temperature)
-            .presence_penalty(settings.llm_presence_penalty)
-            .max_completion_tokens(settings.llm_max_completion_tokens)
-            .build()?;
-        self.complete(req, prefix).await
+    }
+
+    #[tokio::test]
+    async fn test_stream_restores_redactions() {
+        let backend = Arc::new(crate::testing::MockBackend::new(&[
+            "Sure, writing to [EMA",
+            "IL_1]",
+            " now.",
+        ]));
+        let llm = crate::testing::mock_llm(backend.clone(), &["--llm-redact-pii"]);
+        let req = crate::testing::user_request("Write to bob@example.com please");
+
+        let stream = llm.complete_stream(req, None).await.unwrap();
+        let chunks: Vec<_> = stream.map(|c| c.unwrap()).collect().await;
+        let content: String = chunks
+            .iter()
+            .flat_map(|c| c.choices.iter())
+            .filter_map(|c| c.delta.content.as_deref())
+            .collect();
+        assert_eq!(content, "Sure, writing to bob@example.com now.");
+
+        let sent = serde_json::to_string(&backend.requests.lock().unwrap()[0]).unwrap();
+        assert!(sent.contains("[EMAIL_1]"));
+        assert!(!sent.contains("bob@example.com"));
     }
 }
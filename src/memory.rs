@@ -0,0 +1,153 @@
+use std::path::{Path, PathBuf};
+
+use async_openai::types::chat::{
+    ChatCompletionRequestDeveloperMessageContent, ChatCompletionRequestDeveloperMessageContentPart,
+    ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPartText,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestSystemMessageContent,
+    ChatCompletionRequestSystemMessageContentPart, CreateChatCompletionRequest,
+};
+
+use crate::error::PromptError;
+
+pub const DEFAULT_MEMORY_FILES: &[&str] = &["AGENTS.md", "CLAUDE.md"];
+
+/// First memory file found under `root`, trying `names` in order.
+pub fn discover_memory_file(root: &Path, names: &[String]) -> Option<PathBuf> {
+    names
+        .iter()
+        .map(|name| root.join(name))
+        .find(|path| path.is_file())
+}
+
+pub fn load_memory(root: &Path, names: &[String]) -> Result<Option<String>, PromptError> {
+    match discover_memory_file(root, names) {
+        Some(path) => {
+            let content = std::fs::read_to_string(&path)?;
+            Ok(Some(format!(
+                "<project-memory source=\"{}\">\n{}\n</project-memory>",
+                path.display(),
+                content.trim()
+            )))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Append the memory to the leading system/developer message, or add a system
+/// message in front when there is none.
+pub fn inject_memory(
+    req: &mut CreateChatCompletionRequest,
+    memory: &str,
+) -> Result<(), PromptError> {
+    match req.messages.first_mut() {
+        Some(ChatCompletionRequestMessage::System(sys)) => match &mut sys.content {
+            ChatCompletionRequestSystemMessageContent::Text(t) => {
+                t.push_str("\n\n");
+                t.push_str(memory);
+            }
+            ChatCompletionRequestSystemMessageContent::Array(arr) => {
+                arr.push(ChatCompletionRequestSystemMessageContentPart::Text(
+                    ChatCompletionRequestMessageContentPartText {
+                        text: memory.to_string(),
+                    },
+                ));
+            }
+        },
+        Some(ChatCompletionRequestMessage::Developer(dev)) => match &mut dev.content {
+            ChatCompletionRequestDeveloperMessageContent::Text(t) => {
+                t.push_str("\n\n");
+                t.push_str(memory);
+            }
+            ChatCompletionRequestDeveloperMessageContent::Array(arr) => {
+                arr.push(ChatCompletionRequestDeveloperMessageContentPart::Text(
+                    ChatCompletionRequestMessageContentPartText {
+                        text: memory.to_string(),
+                    },
+                ));
+            }
+        },
+        _ => {
+            let sys = ChatCompletionRequestSystemMessageArgs::default()
+                .content(memory)
+                .build()?;
+            req.messages.insert(0, sys.into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::types::chat::ChatCompletionRequestUserMessageArgs;
+
+    use super::*;
+    use crate::{llm::message_text, testing::temp_dir};
+
+    fn names() -> Vec<String> {
+        DEFAULT_MEMORY_FILES.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn test_discover_priority() {
+        let root = temp_dir("memory");
+        assert_eq!(discover_memory_file(&root, &names()), None);
+
+        std::fs::write(root.join("CLAUDE.md"), "second").unwrap();
+        assert_eq!(
+            discover_memory_file(&root, &names()),
+            Some(root.join("CLAUDE.md"))
+        );
+        std::fs::write(root.join("AGENTS.md"), "  first\n").unwrap();
+        assert_eq!(
+            discover_memory_file(&root, &names()),
+            Some(root.join("AGENTS.md"))
+        );
+        // A directory with the name is not a memory file.
+        let nested = temp_dir("memory-dir");
+        std::fs::create_dir(nested.join("AGENTS.md")).unwrap();
+        assert_eq!(discover_memory_file(&nested, &names()), None);
+
+        let memory = load_memory(&root, &names()).unwrap().unwrap();
+        assert!(memory.ends_with(">\nfirst\n</project-memory>"));
+    }
+
+    #[test]
+    fn test_inject_into_system() {
+        let mut req = crate::testing::user_request("hi");
+        req.messages.insert(
+            0,
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content("Be brief.")
+                .build()
+                .unwrap()
+                .into(),
+        );
+        inject_memory(&mut req, "MEMORY").unwrap();
+        assert_eq!(req.messages.len(), 2);
+        assert!(matches!(
+            req.messages[0],
+            ChatCompletionRequestMessage::System(_)
+        ));
+        assert_eq!(message_text(&req.messages[0]), "Be brief.\n\nMEMORY");
+        assert_eq!(message_text(&req.messages[1]), "hi");
+    }
+
+    #[test]
+    fn test_inject_without_system() {
+        let mut req = crate::testing::user_request("hi");
+        req.messages.push(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content("again")
+                .build()
+                .unwrap()
+                .into(),
+        );
+        inject_memory(&mut req, "MEMORY").unwrap();
+        assert_eq!(req.messages.len(), 3);
+        assert!(matches!(
+            req.messages[0],
+            ChatCompletionRequestMessage::System(_)
+        ));
+        assert_eq!(message_text(&req.messages[0]), "MEMORY");
+    }
+}
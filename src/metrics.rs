@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use metrics::{
+    Unit, counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram,
+};
+
+use crate::llm::BillingEntry;
+
+pub const REQUESTS: &str = "openai_requests_total";
+pub const RETRIES: &str = "openai_retries_total";
+pub const LATENCY: &str = "openai_request_duration_seconds";
+pub const TOKENS: &str = "openai_tokens_total";
+pub const COST: &str = "openai_cost_usd";
+
+/// Register units and help texts with the installed recorder, call it once after
+/// installing e.g. a Prometheus exporter.
+pub fn describe() {
+    describe_counter!(REQUESTS, "Completion requests by outcome");
+    describe_counter!(RETRIES, "Completion retries");
+    describe_histogram!(LATENCY, Unit::Seconds, "Completion request latency");
+    describe_counter!(TOKENS, "Billed tokens by kind");
+    describe_gauge!(COST, "Billed USD since start");
+}
+
+pub(crate) fn record_request(model: &str, prefix: &str, elapsed: Duration, outcome: &'static str) {
+    let labels = [
+        ("model", model.to_string()),
+        ("prefix", prefix.to_string()),
+        ("outcome", outcome.to_string()),
+    ];
+    counter!(REQUESTS, &labels).increment(1);
+    histogram!(LATENCY, &labels).record(elapsed.as_secs_f64());
+}
+
+pub(crate) fn record_retry(model: &str, prefix: &str) {
+    counter!(RETRIES, "model" => model.to_string(), "prefix" => prefix.to_string()).increment(1);
+}
+
+pub(crate) fn record_billing(entry: &BillingEntry) {
+    for (kind, tokens) in [
+        ("input", entry.input_tokens),
+        ("cached", entry.cached_tokens),
+        ("output", entry.output_tokens),
+        ("reasoning", entry.reasoning_tokens),
+    ] {
+        counter!(
+            TOKENS,
+            "model" => entry.model.clone(),
+            "prefix" => entry.prefix.clone(),
+            "kind" => kind
+        )
+        .increment(tokens);
+    }
+    gauge!(COST, "model" => entry.model.clone(), "prefix" => entry.prefix.clone())
+        .increment(entry.cost);
+}
@@ -0,0 +1,83 @@
+use chrono::NaiveDate;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::OpenAIModel;
+
+/// All built-in models, i.e. everything except [`OpenAIModel::Other`].
+pub fn known_models() -> Vec<OpenAIModel> {
+    vec![
+        OpenAIModel::GPT4O,
+        OpenAIModel::GPT4OMINI,
+        OpenAIModel::O1,
+        OpenAIModel::O1MINI,
+        OpenAIModel::GPT35TURBO,
+        OpenAIModel::GPT4,
+        OpenAIModel::GPT4TURBO,
+        OpenAIModel::GPT5MINI,
+        OpenAIModel::GPT5NANO,
+        OpenAIModel::GPT52,
+        OpenAIModel::GPT5,
+        OpenAIModel::GPT51,
+        OpenAIModel::GPT5PRO,
+        OpenAIModel::GPT41,
+        OpenAIModel::GPT41MINI,
+        OpenAIModel::GPT41NANO,
+        OpenAIModel::O3,
+        OpenAIModel::O4MINI,
+        OpenAIModel::O3MINI,
+        OpenAIModel::O3PRO,
+        OpenAIModel::GEMINI3PRO,
+        OpenAIModel::GEMINI3FLASH,
+        OpenAIModel::GEMINI25PRO,
+        OpenAIModel::GEMINI25FLASH,
+        OpenAIModel::CLAUDEOPUS45,
+        OpenAIModel::CLAUDESONNET45,
+        OpenAIModel::CLAUDEHAIKU45,
+    ]
+}
+
+/// One line of a model listing, prices in USD per 1M tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "tabled", derive(tabled::Tabled))]
+#[cfg_attr(
+    feature = "tabled",
+    tabled(display(Option, "tabled::derive::display::option", "-"))
+)]
+pub struct Row {
+    pub name: String,
+    pub aliases: String,
+    pub input: f64,
+    pub cached_input: Option<f64>,
+    pub output: f64,
+    pub batch_input: Option<f64>,
+    pub batch_output: Option<f64>,
+    pub context_window: Option<u64>,
+    pub max_output_tokens: Option<u64>,
+    pub knowledge_cutoff: Option<NaiveDate>,
+}
+
+impl From<&OpenAIModel> for Row {
+    fn from(model: &OpenAIModel) -> Self {
+        let pricing = model.pricing();
+        let batch = model.batch_pricing();
+        let info = model.info();
+        Self {
+            name: model.to_string(),
+            aliases: model.aliases().iter().join(", "),
+            input: pricing.input_tokens,
+            cached_input: pricing.cached_input_tokens,
+            output: pricing.output_tokens,
+            batch_input: batch.map(|b| b.input_tokens),
+            batch_output: batch.map(|b| b.output_tokens),
+            context_window: info.map(|i| i.context_window),
+            max_output_tokens: info.map(|i| i.max_output_tokens),
+            knowledge_cutoff: info.map(|i| i.knowledge_cutoff),
+        }
+    }
+}
+
+/// Pricing and capabilities of all built-in models, with synced pricing applied.
+pub fn pricing_table() -> Vec<Row> {
+    known_models().iter().map(Row::from).collect()
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{LazyLock, RwLock},
+};
+
+use color_eyre::eyre::eyre;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::{OpenAIModel, PricingInfo, error::PromptError};
+
+static PRICING_OVERRIDES: LazyLock<RwLock<HashMap<String, PricingInfo>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Pricing merged from a registry for the given model name, if any.
+pub fn pricing_override(model: &str) -> Option<PricingInfo> {
+    PRICING_OVERRIDES
+        .read()
+        .expect("poisoned")
+        .get(model)
+        .copied()
+}
+
+pub fn set_pricing_override(model: &str, pricing: PricingInfo) {
+    PRICING_OVERRIDES
+        .write()
+        .expect("poisoned")
+        .insert(model.to_string(), pricing);
+}
+
+/// Where to fetch the pricing registry, a json object of model name to [`PricingInfo`]
+/// in USD per 1M tokens, and how much to trust it.
+#[derive(Debug, Clone)]
+pub struct PricingSync {
+    pub url: String,
+    /// Expected hex sha256 of the registry body.
+    pub sha256: Option<String>,
+    /// Entries of built-in models deviating more than this factor are rejected.
+    pub max_ratio: f64,
+}
+
+impl PricingSync {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            sha256: None,
+            max_ratio: 10.0,
+        }
+    }
+}
+
+fn check_entry(name: &str, pricing: &PricingInfo, max_ratio: f64) -> Result<(), String> {
+    let prices = [
+        Some(pricing.input_tokens),
+        Some(pricing.output_tokens),
+        pricing.cached_input_tokens,
+    ];
+    if prices.iter().flatten().any(|p| !p.is_finite() || *p < 0.0) {
+        return Err("negative or non-finite price".to_string());
+    }
+    if pricing
+        .cached_input_tokens
+        .is_some_and(|c| c > pricing.input_tokens)
+    {
+        return Err("cached input price above input price".to_string());
+    }
+
+    let model = OpenAIModel::from_str(name).map_err(|e| e.to_string())?;
+    if !matches!(model, OpenAIModel::Other(..)) {
+        let builtin = model.builtin_pricing();
+        for (old, new) in [
+            (builtin.input_tokens, pricing.input_tokens),
+            (builtin.output_tokens, pricing.output_tokens),
+        ] {
+            if old > 0.0 && (new > old * max_ratio || new < old / max_ratio) {
+                return Err(format!("price {} deviates too much from {}", new, old));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Merge already fetched registry content into the pricing table, returns the number
+/// of entries merged. Entries failing the integrity checks are skipped.
+pub fn merge_pricing(body: &[u8], sync: &PricingSync) -> Result<usize, PromptError> {
+    if let Some(expected) = sync.sha256.as_ref() {
+        let digest = Sha256::digest(body)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        if !digest.eq_ignore_ascii_case(expected) {
+            return Err(PromptError::Other(eyre!(
+                "pricing registry checksum mismatch, expected {} got {}",
+                expected,
+                digest
+            )));
+        }
+    }
+
+    let registry: HashMap<String, PricingInfo> = serde_json::from_slice(body)?;
+    let mut merged = 0;
+    for (name, pricing) in registry.into_iter() {
+        match check_entry(&name, &pricing, sync.max_ratio) {
+            Ok(_) => {
+                set_pricing_override(&name, pricing);
+                merged += 1;
+            }
+            Err(e) => warn!("Skip pricing of {} from registry: {}", name, e),
+        }
+    }
+    Ok(merged)
+}
+
+/// Fetch the pricing registry and merge it into the pricing table.
+pub async fn sync_pricing(sync: &PricingSync) -> Result<usize, PromptError> {
+    let body = reqwest::get(&sync.url)
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| eyre!("fail to fetch pricing registry: {}", e))?
+        .bytes()
+        .await
+        .map_err(|e| eyre!("fail to read pricing registry: {}", e))?;
+    let merged = merge_pricing(&body, sync)?;
+    info!("Merged {} pricing entries from {}", merged, &sync.url);
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pricing(input: f64, output: f64, cached: Option<f64>) -> PricingInfo {
+        PricingInfo {
+            input_tokens: input,
+            output_tokens: output,
+            cached_input_tokens: cached,
+        }
+    }
+
+    #[test]
+    fn test_check_entry() {
+        assert!(check_entry("gpt-4o", &pricing(2.5, 10.0, Some(1.25)), 10.0).is_ok());
+        assert!(check_entry("gpt-4o", &pricing(-1.0, 10.0, None), 10.0).is_err());
+        assert!(check_entry("gpt-4o", &pricing(f64::NAN, 10.0, None), 10.0).is_err());
+        assert!(check_entry("gpt-4o", &pricing(2.5, 10.0, Some(3.0)), 10.0).is_err());
+        // Built-in models reject prices far off the known ones.
+        assert!(check_entry("gpt-4o", &pricing(250.0, 10.0, None), 10.0).is_err());
+        assert!(check_entry("gpt-4o", &pricing(0.1, 10.0, None), 10.0).is_err());
+        // Unknown models have nothing to compare with.
+        assert!(check_entry("pricing-test-any", &pricing(250.0, 10.0, None), 10.0).is_ok());
+    }
+
+    #[test]
+    fn test_merge_pricing() {
+        let body = br#"{
+            "pricing-test-good": {"input_tokens": 1.0, "output_tokens": 2.0, "cached_input_tokens": null},
+            "pricing-test-bad": {"input_tokens": -1.0, "output_tokens": 2.0, "cached_input_tokens": null}
+        }"#;
+        let sync = PricingSync::new("unused");
+        assert_eq!(merge_pricing(body, &sync).unwrap(), 1);
+        assert_eq!(
+            pricing_override("pricing-test-good").map(|p| p.output_tokens),
+            Some(2.0)
+        );
+        assert!(pricing_override("pricing-test-bad").is_none());
+        let model: OpenAIModel = "pricing-test-good".parse().unwrap();
+        assert_eq!(model.pricing().input_tokens, 1.0);
+    }
+
+    #[test]
+    fn test_merge_pricing_checksum() {
+        let body = br#"{"pricing-test-sum": {"input_tokens": 1.0, "output_tokens": 2.0, "cached_input_tokens": null}}"#;
+        let mut sync = PricingSync::new("unused");
+        sync.sha256 = Some("00".repeat(32));
+        assert!(merge_pricing(body, &sync).is_err());
+        assert!(pricing_override("pricing-test-sum").is_none());
+
+        let digest = Sha256::digest(body)
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        sync.sha256 = Some(digest);
+        assert_eq!(merge_pricing(body, &sync).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_merge_pricing_invalid_json() {
+        assert!(merge_pricing(b"[1, 2]", &PricingSync::new("unused")).is_err());
+    }
+}
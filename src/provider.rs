@@ -0,0 +1,304 @@
+use std::str::FromStr;
+
+use async_openai::types::chat::{
+    ChatCompletionNamedToolChoice, ChatCompletionRequestMessage,
+    ChatCompletionRequestMessageContentPartText, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestUserMessageArgs, ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart, ChatCompletionToolChoiceOption,
+    CreateChatCompletionRequest, FunctionName,
+};
+use derive_more::derive::Display;
+use serde::{Deserialize, Serialize};
+
+use crate::llm::message_text;
+
+/// Known OpenAI-compatible gateways, used to adjust request quirks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Display)]
+pub enum Provider {
+    #[default]
+    #[display("openai")]
+    OpenAI,
+    #[display("azure")]
+    Azure,
+    #[display("openrouter")]
+    OpenRouter,
+    #[display("together")]
+    Together,
+    #[display("groq")]
+    Groq,
+    #[display("vllm")]
+    VLLM,
+    #[display("ollama")]
+    Ollama,
+    #[display("llama.cpp")]
+    LlamaCpp,
+    /// Claude messages API, requests are translated rather than adjusted.
+    #[display("anthropic")]
+    Anthropic,
+}
+
+impl FromStr for Provider {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "openai" => Ok(Self::OpenAI),
+            "azure" => Ok(Self::Azure),
+            "openrouter" => Ok(Self::OpenRouter),
+            "together" | "togetherai" => Ok(Self::Together),
+            "groq" => Ok(Self::Groq),
+            "vllm" => Ok(Self::VLLM),
+            "ollama" => Ok(Self::Ollama),
+            "llama.cpp" | "llamacpp" | "llama-cpp" => Ok(Self::LlamaCpp),
+            "anthropic" | "claude" => Ok(Self::Anthropic),
+            _ => Err(format!("unknown provider: {}", s)),
+        }
+    }
+}
+
+/// How instruction roles are sent to servers rejecting some of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Display)]
+pub enum RoleMapping {
+    #[default]
+    #[display("keep")]
+    Keep,
+    /// Send `developer` messages as `system`.
+    #[display("system")]
+    DeveloperAsSystem,
+    /// Prepend all `system` and `developer` messages to the first user message.
+    #[display("user")]
+    MergeIntoFirstUser,
+}
+
+impl FromStr for RoleMapping {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keep" => Ok(Self::Keep),
+            "system" => Ok(Self::DeveloperAsSystem),
+            "user" => Ok(Self::MergeIntoFirstUser),
+            _ => Err(format!("unknown role mapping: {}", s)),
+        }
+    }
+}
+
+impl RoleMapping {
+    pub fn apply(&self, req: &mut CreateChatCompletionRequest) {
+        match self {
+            Self::Keep => {}
+            Self::DeveloperAsSystem => {
+                for msg in req.messages.iter_mut() {
+                    if let ChatCompletionRequestMessage::Developer(dev) = &*msg {
+                        let system = ChatCompletionRequestSystemMessage {
+                            content: message_text(msg).into(),
+                            name: dev.name.clone(),
+                        };
+                        *msg = system.into();
+                    }
+                }
+            }
+            Self::MergeIntoFirstUser => {
+                let (instructions, mut messages): (Vec<_>, Vec<_>) =
+                    req.messages.drain(..).partition(|msg| {
+                        matches!(
+                            msg,
+                            ChatCompletionRequestMessage::System(_)
+                                | ChatCompletionRequestMessage::Developer(_)
+                        )
+                    });
+                if !instructions.is_empty() {
+                    let text = instructions
+                        .iter()
+                        .map(message_text)
+                        .collect::<Vec<_>>()
+                        .join("\n\n");
+                    match messages
+                        .iter_mut()
+                        .find(|msg| matches!(msg, ChatCompletionRequestMessage::User(_)))
+                    {
+                        Some(ChatCompletionRequestMessage::User(usr)) => {
+                            usr.content = match &usr.content {
+                                ChatCompletionRequestUserMessageContent::Text(t) => {
+                                    format!("{}\n\n{}", text, t).into()
+                                }
+                                ChatCompletionRequestUserMessageContent::Array(parts) => {
+                                    let mut merged =
+                                        vec![ChatCompletionRequestUserMessageContentPart::Text(
+                                            ChatCompletionRequestMessageContentPartText { text },
+                                        )];
+                                    merged.extend(parts.iter().cloned());
+                                    merged.into()
+                                }
+                            };
+                        }
+                        _ => {
+                            if let Ok(usr) = ChatCompletionRequestUserMessageArgs::default()
+                                .content(text)
+                                .build()
+                            {
+                                messages.insert(0, usr.into());
+                            }
+                        }
+                    }
+                }
+                req.messages = messages;
+            }
+        }
+    }
+}
+
+/// Request adjustments needed by a provider.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProviderQuirks {
+    /// Send `max_tokens` instead of `max_completion_tokens`.
+    pub legacy_max_tokens: bool,
+    /// Named tool choice uses the `function` dialect instead of `custom`.
+    pub function_tool_choice: bool,
+    /// Drop OpenAI-only fields: store, metadata, service_tier, prompt_cache_key, safety_identifier.
+    pub drop_openai_only: bool,
+    pub drop_logprobs: bool,
+    pub drop_reasoning_effort: bool,
+    /// Let the server decide whether to call tools.
+    pub drop_tool_choice: bool,
+    /// Responses usually carry no usage, so it is estimated instead.
+    pub no_usage: bool,
+    pub role_mapping: RoleMapping,
+}
+
+impl Provider {
+    pub fn quirks(&self) -> ProviderQuirks {
+        match self {
+            Self::OpenAI | Self::Azure | Self::Anthropic => ProviderQuirks::default(),
+            Self::OpenRouter => ProviderQuirks {
+                legacy_max_tokens: true,
+                function_tool_choice: true,
+                drop_openai_only: true,
+                role_mapping: RoleMapping::DeveloperAsSystem,
+                ..Default::default()
+            },
+            Self::Together => ProviderQuirks {
+                legacy_max_tokens: true,
+                function_tool_choice: true,
+                drop_openai_only: true,
+                drop_reasoning_effort: true,
+                role_mapping: RoleMapping::DeveloperAsSystem,
+                ..Default::default()
+            },
+            Self::Groq => ProviderQuirks {
+                function_tool_choice: true,
+                drop_openai_only: true,
+                drop_logprobs: true,
+                role_mapping: RoleMapping::DeveloperAsSystem,
+                ..Default::default()
+            },
+            Self::VLLM => ProviderQuirks {
+                legacy_max_tokens: true,
+                function_tool_choice: true,
+                drop_openai_only: true,
+                drop_reasoning_effort: true,
+                role_mapping: RoleMapping::DeveloperAsSystem,
+                ..Default::default()
+            },
+            Self::Ollama => ProviderQuirks {
+                legacy_max_tokens: true,
+                drop_openai_only: true,
+                drop_logprobs: true,
+                drop_reasoning_effort: true,
+                drop_tool_choice: true,
+                no_usage: true,
+                role_mapping: RoleMapping::DeveloperAsSystem,
+                ..Default::default()
+            },
+            Self::LlamaCpp => ProviderQuirks {
+                legacy_max_tokens: true,
+                drop_openai_only: true,
+                drop_reasoning_effort: true,
+                drop_tool_choice: true,
+                no_usage: true,
+                role_mapping: RoleMapping::DeveloperAsSystem,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[allow(deprecated)]
+    pub fn adjust_request(&self, req: &mut CreateChatCompletionRequest) {
+        let quirks = self.quirks();
+
+        if quirks.legacy_max_tokens && req.max_completion_tokens.is_some() {
+            req.max_tokens = req.max_completion_tokens.take();
+        }
+        if quirks.function_tool_choice
+            && let Some(ChatCompletionToolChoiceOption::Custom(custom)) = req.tool_choice.as_ref()
+        {
+            req.tool_choice = Some(ChatCompletionToolChoiceOption::Function(
+                ChatCompletionNamedToolChoice {
+                    function: FunctionName {
+                        name: custom.custom.name.clone(),
+                    },
+                },
+            ));
+        }
+        if quirks.drop_openai_only {
+            req.store = None;
+            req.metadata = None;
+            req.service_tier = None;
+            req.prompt_cache_key = None;
+            req.safety_identifier = None;
+        }
+        if quirks.drop_logprobs {
+            req.logprobs = None;
+            req.top_logprobs = None;
+            req.logit_bias = None;
+        }
+        if quirks.drop_reasoning_effort {
+            req.reasoning_effort = None;
+        }
+        if quirks.drop_tool_choice {
+            req.tool_choice = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(messages: serde_json::Value) -> CreateChatCompletionRequest {
+        serde_json::from_value(json!({"model": "m", "messages": messages})).unwrap()
+    }
+
+    #[test]
+    fn test_developer_as_system() {
+        let mut req = request(json!([
+            {"role": "developer", "content": [
+                {"type": "text", "text": "be brief"},
+                {"type": "text", "text": "a\n</b>"},
+            ]},
+            {"role": "user", "content": "hi"},
+        ]));
+        RoleMapping::DeveloperAsSystem.apply(&mut req);
+        let ChatCompletionRequestMessage::System(sys) = &req.messages[0] else {
+            panic!("not a system message");
+        };
+        assert_eq!(
+            serde_json::to_value(&sys.content).unwrap(),
+            json!("be brief\na\n</b>")
+        );
+    }
+
+    #[test]
+    fn test_merge_into_first_user() {
+        let mut req = request(json!([
+            {"role": "system", "content": "rules\n</rules>"},
+            {"role": "user", "content": "question"},
+        ]));
+        RoleMapping::MergeIntoFirstUser.apply(&mut req);
+        assert_eq!(req.messages.len(), 1);
+        assert_eq!(
+            message_text(&req.messages[0]),
+            "rules\n</rules>\n\nquestion"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use std::{sync::LazyLock, time::Duration};
+
+use async_openai::error::OpenAIError;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::error::PromptError;
+
+// async-openai drops the response headers, so the hints come from the error body, e.g.
+// "Rate limit reached for gpt-4o in organization org-x on tokens per min (TPM):
+// Limit 30000, Used 28764, Requested 1581. Please try again in 690ms."
+static LIMITS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)on (tokens|requests) per min[^:]*:\s*Limit (\d+), Used (\d+)")
+        .expect("valid regex")
+});
+static TRY_AGAIN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)try again in (?:(\d+)m(?:in)?\s*)?(\d+(?:\.\d+)?)(ms|s)\b")
+        .expect("valid regex")
+});
+
+/// Latest rate limit state reported by the provider.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RateLimitInfo {
+    pub limit_requests: Option<u64>,
+    pub remaining_requests: Option<u64>,
+    pub limit_tokens: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    /// How long the provider asked to wait.
+    pub retry_after: Option<Duration>,
+    pub time: Option<DateTime<Utc>>,
+}
+
+fn is_rate_limit(err: &async_openai::error::ApiError) -> bool {
+    err.code.as_deref() == Some("rate_limit_exceeded")
+        || err.r#type.as_deref() == Some("rate_limit_exceeded")
+        || err.message.to_lowercase().contains("rate limit")
+}
+
+fn retry_after(message: &str) -> Option<Duration> {
+    let caps = TRY_AGAIN.captures(message)?;
+    let minutes: f64 = caps.get(1).map_or(Ok(0.0), |m| m.as_str().parse()).ok()?;
+    let value: f64 = caps[2].parse().ok()?;
+    let secs = match &caps[3] {
+        "ms" => value / 1000.0,
+        _ => value,
+    };
+    Some(Duration::from_secs_f64(minutes * 60.0 + secs))
+}
+
+impl RateLimitInfo {
+    /// Rate limit details of a rate limit error, None for other errors.
+    pub fn from_error(err: &PromptError) -> Option<Self> {
+        let Some(OpenAIError::ApiError(api)) = err.openai_error() else {
+            return None;
+        };
+        if !is_rate_limit(api) {
+            return None;
+        }
+
+        let mut info = Self {
+            retry_after: retry_after(&api.message),
+            time: Some(Utc::now()),
+            ..Default::default()
+        };
+        if let Some(caps) = LIMITS.captures(&api.message) {
+            let limit: Option<u64> = caps[2].parse().ok();
+            let remaining = caps[3]
+                .parse::<u64>()
+                .ok()
+                .zip(limit)
+                .map(|(used, limit)| limit.saturating_sub(used));
+            if caps[1].eq_ignore_ascii_case("tokens") {
+                info.limit_tokens = limit;
+                info.remaining_tokens = remaining;
+            } else {
+                info.limit_requests = limit;
+                info.remaining_requests = remaining;
+            }
+        }
+        Some(info)
+    }
+}
+
+/// Token bucket over spent USD: completions wait while the spend outruns
+/// `usd_per_hour`, with up to `burst` USD allowed at once. Unlike the billing caps it
+/// slows a runaway loop down instead of failing it.
+#[derive(Debug)]
+pub struct SpendLimiter {
+    pub usd_per_hour: f64,
+    pub burst: f64,
+    // USD left in the bucket, negative when in debt, and when it was last refilled.
+    bucket: std::sync::Mutex<(f64, tokio::time::Instant)>,
+}
+
+impl SpendLimiter {
+    pub fn new(usd_per_hour: f64, burst: f64) -> Self {
+        Self {
+            usd_per_hour,
+            burst,
+            bucket: std::sync::Mutex::new((burst, tokio::time::Instant::now())),
+        }
+    }
+
+    // Refill the locked bucket for the time elapsed and return the USD left.
+    fn refill(&self, bucket: &mut (f64, tokio::time::Instant)) -> f64 {
+        let now = tokio::time::Instant::now();
+        let refilled = now.duration_since(bucket.1).as_secs_f64() / 3600.0 * self.usd_per_hour;
+        *bucket = ((bucket.0 + refilled).min(self.burst), now);
+        bucket.0
+    }
+
+    pub fn charge(&self, usd: f64) {
+        let mut bucket = self.bucket.lock().expect("poisoned");
+        self.refill(&mut bucket);
+        bucket.0 -= usd;
+    }
+
+    /// How long until the bucket is out of debt, zero if it is not.
+    pub fn wait_time(&self) -> Duration {
+        let left = self.refill(&mut self.bucket.lock().expect("poisoned"));
+        if left >= 0.0 || self.usd_per_hour <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-left / self.usd_per_hour * 3600.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_spend_limiter() {
+        let limiter = SpendLimiter::new(3.6, 1.0);
+        assert_eq!(limiter.wait_time(), Duration::ZERO);
+        limiter.charge(1.5);
+        // 0.5 USD of debt at 1 USD per 1000s.
+        assert_eq!(limiter.wait_time().as_secs(), 500);
+        tokio::time::advance(Duration::from_secs(200)).await;
+        assert_eq!(limiter.wait_time().as_secs(), 300);
+        tokio::time::advance(Duration::from_secs(10_000)).await;
+        assert_eq!(limiter.wait_time(), Duration::ZERO);
+        // The refill stops at the burst.
+        limiter.charge(1.0);
+        assert_eq!(limiter.wait_time(), Duration::ZERO);
+        limiter.charge(0.1);
+        assert_eq!(limiter.wait_time().as_secs(), 100);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(
+            retry_after("Please try again in 690ms."),
+            Some(Duration::from_millis(690))
+        );
+        assert_eq!(
+            retry_after("Please try again in 1.5s."),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            retry_after("Please try again in 2m30s."),
+            Some(Duration::from_secs(150))
+        );
+        assert_eq!(
+            retry_after("Please try again in 1min 5s."),
+            Some(Duration::from_secs(65))
+        );
+        assert_eq!(retry_after("Please try again later."), None);
+    }
+
+    #[test]
+    fn test_from_error() {
+        let err = PromptError::from_provider(OpenAIError::ApiError(
+            async_openai::error::ApiError {
+                message: "Rate limit reached for gpt-4o in organization org-x on tokens per min (TPM): Limit 30000, Used 28764, Requested 1581. Please try again in 690ms.".to_string(),
+                r#type: Some("tokens".to_string()),
+                param: None,
+                code: Some("rate_limit_exceeded".to_string()),
+            },
+        ));
+        let info = RateLimitInfo::from_error(&err).unwrap();
+        assert_eq!(info.limit_tokens, Some(30000));
+        assert_eq!(info.remaining_tokens, Some(1236));
+        assert_eq!(info.limit_requests, None);
+        assert_eq!(info.retry_after, Some(Duration::from_millis(690)));
+
+        let other =
+            PromptError::from_provider(OpenAIError::ApiError(async_openai::error::ApiError {
+                message: "invalid model".to_string(),
+                r#type: None,
+                param: None,
+                code: None,
+            }));
+        assert!(RateLimitInfo::from_error(&other).is_none());
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+
+use async_openai::types::chat::{
+    ChatCompletionMessageToolCalls, ChatCompletionRequestMessage,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+    CreateChatCompletionRequest, CreateChatCompletionResponse, CreateChatCompletionStreamResponse,
+};
+use regex::Regex;
+
+use crate::error::PromptError;
+
+/// Placeholder mapping kept locally so that redacted values can be restored, one per
+/// request so placeholders never leak across requests.
+#[derive(Debug, Clone, Default)]
+pub struct RedactionMap {
+    pub placeholders: HashMap<String, String>,
+    pub originals: HashMap<String, String>,
+    counters: HashMap<String, u64>,
+}
+
+impl RedactionMap {
+    fn placeholder(&mut self, label: &str, original: &str) -> String {
+        if let Some(ph) = self.placeholders.get(original) {
+            return ph.clone();
+        }
+        let cnt = self.counters.entry(label.to_string()).or_default();
+        *cnt += 1;
+        let ph = format!("[{}_{}]", label, cnt);
+        self.placeholders.insert(original.to_string(), ph.clone());
+        self.originals.insert(ph.clone(), original.to_string());
+        ph
+    }
+
+    pub fn restore(&self, text: &str) -> String {
+        let mut s = text.to_string();
+        for (ph, original) in self.originals.iter() {
+            s = s.replace(ph, original);
+        }
+        s
+    }
+
+    // Like `restore` inside json strings, originals are escaped.
+    fn restore_json(&self, json: &str) -> String {
+        let mut s = json.to_string();
+        for (ph, original) in self.originals.iter() {
+            let escaped = serde_json::to_string(original).unwrap_or_default();
+            let escaped = escaped
+                .strip_prefix('"')
+                .and_then(|e| e.strip_suffix('"'))
+                .unwrap_or(original);
+            s = s.replace(ph, escaped);
+        }
+        s
+    }
+
+    // Start of a trailing `[LABEL_` fragment that may still grow into a placeholder.
+    fn partial_placeholder(&self, text: &str) -> Option<usize> {
+        let start = text.rfind('[')?;
+        let tail = &text[start..];
+        self.originals
+            .keys()
+            .any(|ph| ph.len() > tail.len() && ph.starts_with(tail))
+            .then_some(start)
+    }
+
+    /// Put the originals back into the content and tool call arguments of `resp`.
+    pub fn restore_response(&self, resp: &mut CreateChatCompletionResponse) {
+        for choice in resp.choices.iter_mut() {
+            if let Some(content) = choice.message.content.as_mut() {
+                *content = self.restore(content);
+            }
+            for call in choice.message.tool_calls.iter_mut().flatten() {
+                match call {
+                    ChatCompletionMessageToolCalls::Function(f) => {
+                        f.function.arguments = self.restore_json(&f.function.arguments);
+                    }
+                    ChatCompletionMessageToolCalls::Custom(c) => {
+                        c.custom_tool.input = self.restore(&c.custom_tool.input);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Restores the content deltas of a stream, holding back a placeholder split across
+/// chunks until it is complete or the choice finishes.
+#[derive(Debug)]
+pub(crate) struct DeltaRestorer {
+    map: RedactionMap,
+    pending: Vec<String>,
+}
+
+impl DeltaRestorer {
+    pub(crate) fn new(map: RedactionMap) -> Self {
+        Self {
+            map,
+            pending: vec![],
+        }
+    }
+
+    pub(crate) fn map(&self) -> &RedactionMap {
+        &self.map
+    }
+
+    pub(crate) fn restore_chunk(&mut self, chunk: &mut CreateChatCompletionStreamResponse) {
+        for choice in chunk.choices.iter_mut() {
+            let idx = choice.index as usize;
+            if self.pending.len() <= idx {
+                self.pending.resize_with(idx + 1, String::new);
+            }
+            let pending = &mut self.pending[idx];
+            if let Some(delta) = choice.delta.content.as_ref() {
+                pending.push_str(delta);
+            }
+            if pending.is_empty() {
+                continue;
+            }
+            let end = match choice.finish_reason {
+                Some(_) => pending.len(),
+                None => self
+                    .map
+                    .partial_placeholder(pending)
+                    .unwrap_or(pending.len()),
+            };
+            let ready: String = pending.drain(..end).collect();
+            choice.delta.content = Some(self.map.restore(&ready));
+        }
+    }
+}
+
+/// Masks emails, phone numbers, credit-card-like numbers and custom patterns
+/// in outgoing user content.
+#[derive(Debug)]
+pub struct Redactor {
+    patterns: Vec<(String, Regex)>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        // Order matters: card numbers would otherwise be taken as phone numbers.
+        let patterns = [
+            ("EMAIL", r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}"),
+            ("CARD", r"\b(?:\d[ -]?){12,18}\d\b"),
+            (
+                "PHONE",
+                r"\+?\b(?:\d{1,3}[\s.-]?)?\(?\d{3}\)?[\s.-]?\d{3}[\s.-]?\d{4}\b",
+            ),
+        ]
+        .into_iter()
+        .map(|(label, re)| (label.to_string(), Regex::new(re).expect("valid pattern")))
+        .collect();
+
+        Self { patterns }
+    }
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_pattern(mut self, label: &str, pattern: &str) -> Result<Self, PromptError> {
+        let re = Regex::new(pattern).map_err(|e| color_eyre::eyre::eyre!(e))?;
+        self.patterns.push((label.to_uppercase(), re));
+        Ok(self)
+    }
+
+    /// Mask `text`, placeholders are taken from and added to `map`.
+    pub fn redact(&self, text: &str, map: &mut RedactionMap) -> String {
+        let mut s = text.to_string();
+        for (label, re) in self.patterns.iter() {
+            s = re
+                .replace_all(&s, |caps: &regex::Captures| {
+                    map.placeholder(label, &caps[0])
+                })
+                .into_owned();
+        }
+        s
+    }
+
+    /// Mask the user messages of `req`, returns the mapping to restore the response
+    /// with [`RedactionMap::restore_response`].
+    pub fn redact_request(&self, req: &mut CreateChatCompletionRequest) -> RedactionMap {
+        let mut map = RedactionMap::default();
+        for msg in req.messages.iter_mut() {
+            if let ChatCompletionRequestMessage::User(usr) = msg {
+                match &mut usr.content {
+                    ChatCompletionRequestUserMessageContent::Text(t) => {
+                        *t = self.redact(t, &mut map)
+                    }
+                    ChatCompletionRequestUserMessageContent::Array(arr) => {
+                        for part in arr.iter_mut() {
+                            if let ChatCompletionRequestUserMessageContentPart::Text(t) = part {
+                                t.text = self.redact(&t.text, &mut map);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_redact_patterns() {
+        let redactor = Redactor::new();
+        let mut map = RedactionMap::default();
+        let text = "mail a.b@example.com or bob@example.org, card 4111 1111 1111 1111, \
+            call +1 415-555-0100, again a.b@example.com";
+        let redacted = redactor.redact(text, &mut map);
+        assert_eq!(
+            redacted,
+            "mail [EMAIL_1] or [EMAIL_2], card [CARD_1], call [PHONE_1], again [EMAIL_1]"
+        );
+        assert_eq!(map.restore(&redacted), text);
+    }
+
+    #[test]
+    fn test_custom_pattern() {
+        let redactor = Redactor::new().with_pattern("ticket", r"TKT-\d+").unwrap();
+        let mut map = RedactionMap::default();
+        assert_eq!(redactor.redact("see TKT-42", &mut map), "see [TICKET_1]");
+        assert!(Redactor::new().with_pattern("bad", "(").is_err());
+    }
+
+    #[test]
+    fn test_request_maps_are_separate() {
+        let redactor = Redactor::new();
+        let request = |text: &str| -> CreateChatCompletionRequest {
+            serde_json::from_value(json!({
+                "model": "m",
+                "messages": [
+                    {"role": "system", "content": "admin@example.com"},
+                    {"role": "user", "content": text},
+                ],
+            }))
+            .unwrap()
+        };
+        let mut first = request("I am alice@example.com");
+        let first_map = redactor.redact_request(&mut first);
+        let mut second = request("I am bob@example.com");
+        let second_map = redactor.redact_request(&mut second);
+
+        // System messages are not user content and left as is.
+        assert_eq!(
+            serde_json::to_value(&first.messages[0]).unwrap()["content"],
+            json!("admin@example.com")
+        );
+        assert_eq!(
+            serde_json::to_value(&second.messages[1]).unwrap()["content"],
+            json!("I am [EMAIL_1]")
+        );
+        assert_eq!(first_map.restore("[EMAIL_1]"), "alice@example.com");
+        assert_eq!(second_map.restore("[EMAIL_1]"), "bob@example.com");
+    }
+
+    #[test]
+    fn test_restore_response() {
+        // Originals needing escapes in json arguments.
+        let redactor = Redactor::new().with_pattern("nick", r#""\w+""#).unwrap();
+        let mut map = RedactionMap::default();
+        redactor.redact("\"bob\"", &mut map);
+        let mut resp: CreateChatCompletionResponse = serde_json::from_value(json!({
+            "id": "r",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "m",
+            "choices": [{
+                "index": 0,
+                "finish_reason": "tool_calls",
+                "message": {
+                    "role": "assistant",
+                    "content": "writing to [NICK_1]",
+                    "tool_calls": [{
+                        "id": "c",
+                        "type": "function",
+                        "function": {"name": "send", "arguments": "{\"to\":\"[NICK_1]\"}"},
+                    }],
+                },
+            }],
+        }))
+        .unwrap();
+        map.restore_response(&mut resp);
+        let message = &resp.choices[0].message;
+        assert_eq!(message.content.as_deref(), Some("writing to \"bob\""));
+        let Some(ChatCompletionMessageToolCalls::Function(call)) =
+            message.tool_calls.as_ref().and_then(|c| c.first())
+        else {
+            panic!("no tool call");
+        };
+        let args: serde_json::Value = serde_json::from_str(&call.function.arguments).unwrap();
+        assert_eq!(args["to"], json!("\"bob\""));
+    }
+
+    #[test]
+    fn test_restore_split_placeholder() {
+        let mut map = RedactionMap::default();
+        Redactor::new().redact("bob@example.com", &mut map);
+        let mut restorer = DeltaRestorer::new(map);
+
+        let mut contents = vec![];
+        for (piece, finish) in [("Mail [EMA", false), ("IL_1] or [", false), ("x", true)] {
+            let mut chunk = crate::testing::chunk(Some(piece), finish);
+            restorer.restore_chunk(&mut chunk);
+            contents.push(chunk.choices[0].delta.content.clone().unwrap());
+        }
+        assert_eq!(contents, ["Mail ", "bob@example.com or ", "[x"]);
+    }
+}
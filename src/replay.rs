@@ -0,0 +1,171 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use async_openai::types::chat::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use color_eyre::eyre::eyre;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+
+use crate::{error::PromptError, llm::DEBUG_SESSION_FILE};
+
+/// Identifies a request by its messages and tools, so a replay still matches after
+/// switching the model.
+pub fn request_key(req: &CreateChatCompletionRequest) -> String {
+    let body = serde_json::to_string(&(&req.messages, &req.tools)).unwrap_or_default();
+    Sha256::digest(body.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Debug)]
+struct Recorded {
+    key: String,
+    resp: CreateChatCompletionResponse,
+    used: bool,
+}
+
+/// Serves the responses recorded in a `--llm-debug` directory instead of calling a
+/// provider. A request gets the first unused response recorded for the same
+/// messages, or the next unused one in recording order if none matches.
+#[derive(Debug, Clone)]
+pub struct ReplayClient {
+    dir: PathBuf,
+    recorded: Arc<Mutex<Vec<Recorded>>>,
+}
+
+// The dumps of `dir` in recording order, `dir` may be a session directory or the
+// debug root holding several.
+fn dump_files(dir: &Path) -> Result<Vec<PathBuf>, PromptError> {
+    let mut files = vec![];
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            files.extend(dump_files(&path)?);
+        } else if path.extension().is_some_and(|ext| ext == "json")
+            && path.file_name().is_some_and(|f| f != DEBUG_SESSION_FILE)
+        {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+impl ReplayClient {
+    pub fn load(dir: &Path) -> Result<Self, PromptError> {
+        let mut files = dump_files(dir)?;
+        // Dumps end with a zero padded index, "<prefix>-<index>.json".
+        files.sort_by_key(|f| {
+            let stem = f.file_stem().map(|s| s.to_string_lossy().to_string());
+            let index = stem
+                .as_ref()
+                .and_then(|s| s.rsplit_once('-'))
+                .and_then(|(_, idx)| idx.parse::<u64>().ok());
+            (f.parent().map(Path::to_path_buf), index)
+        });
+
+        let mut recorded = vec![];
+        for file in files {
+            let content = std::fs::read_to_string(&file)?;
+            let mut lines = content.lines();
+            let (Some(req), Some(resp)) = (lines.next(), lines.next()) else {
+                debug!("No response in {:?}, not replayed", &file);
+                continue;
+            };
+            match (
+                serde_json::from_str::<CreateChatCompletionRequest>(req),
+                serde_json::from_str(resp),
+            ) {
+                (Ok(req), Ok(resp)) => recorded.push(Recorded {
+                    key: request_key(&req),
+                    resp,
+                    used: false,
+                }),
+                _ => warn!("Fail to parse debug dump {:?}, not replayed", &file),
+            }
+        }
+        debug!(
+            "Loaded {} recorded responses from {:?}",
+            recorded.len(),
+            dir
+        );
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            recorded: Arc::new(Mutex::new(recorded)),
+        })
+    }
+
+    pub async fn create_chat(
+        &self,
+        req: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, PromptError> {
+        let key = request_key(&req);
+        let mut recorded = self
+            .recorded
+            .lock()
+            .map_err(|e| PromptError::Other(eyre!("replay lock poisoned: {}", e)))?;
+        let pos = match recorded.iter().position(|r| !r.used && r.key == key) {
+            Some(pos) => pos,
+            None => {
+                let pos = recorded.iter().position(|r| !r.used).ok_or_else(|| {
+                    PromptError::Other(eyre!("no recorded response left in {:?}", &self.dir))
+                })?;
+                warn!(
+                    "No recorded response matches the request, replaying the next one from {:?}",
+                    &self.dir
+                );
+                pos
+            }
+        };
+        recorded[pos].used = true;
+        Ok(recorded[pos].resp.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{response, temp_dir, user_request};
+
+    fn write_dump(dir: &Path, name: &str, content: &str, answered: bool) {
+        let mut dump = serde_json::to_string(&user_request(content)).unwrap() + "\n";
+        if answered {
+            dump += &serde_json::to_string(&response(&format!("re {}", content))).unwrap();
+        }
+        std::fs::write(dir.join(name), dump).unwrap();
+    }
+
+    async fn answer(client: &ReplayClient, content: &str) -> Option<String> {
+        let resp = client.create_chat(user_request(content)).await.ok()?;
+        resp.choices[0].message.content.clone()
+    }
+
+    #[tokio::test]
+    async fn test_replay_order_and_fallback() {
+        let root = temp_dir("replay");
+        let first = root.join("100-aaaa-llm");
+        let second = root.join("200-bbbb-llm");
+        std::fs::create_dir(&second).unwrap();
+        std::fs::create_dir(&first).unwrap();
+        // Written out of order, the index is not zero padded here.
+        write_dump(&second, "llm-1.json", "one", true);
+        write_dump(&first, "llm-10.json", "ten", true);
+        write_dump(&first, "llm-9.json", "nine", true);
+        write_dump(&first, "llm-11.json", "unanswered", false);
+        std::fs::write(first.join(DEBUG_SESSION_FILE), "{}").unwrap();
+
+        let client = ReplayClient::load(&root).unwrap();
+        assert_eq!(client.recorded.lock().unwrap().len(), 3);
+        // Matching messages first, then the next unused one in recording order.
+        assert_eq!(answer(&client, "ten").await.as_deref(), Some("re ten"));
+        assert_eq!(answer(&client, "other").await.as_deref(), Some("re nine"));
+        assert_eq!(answer(&client, "ten").await.as_deref(), Some("re one"));
+        assert_eq!(answer(&client, "one").await, None);
+    }
+}
@@ -0,0 +1,72 @@
+use futures_util::{StreamExt, TryStreamExt, stream};
+use itertools::Itertools;
+
+use crate::{
+    chunk::{chunk_text, split_trailing_whitespace},
+    error::PromptError,
+    llm::LLMInner,
+    structured::response_content,
+};
+
+const CHUNK_CHARS: usize = 8000;
+const CHUNK_CONCURRENCY: usize = 4;
+
+const PRESERVE_MARKUP: &str = "Preserve all markup exactly: markdown syntax, HTML tags, code blocks, \
+    links and placeholders must stay unchanged. Output only the resulting text without any comments.";
+
+impl LLMInner {
+    /// Translate `text` into `target_lang`, chunking long inputs. Terms in the
+    /// glossary are always translated as given.
+    pub async fn translate(
+        &self,
+        text: &str,
+        target_lang: &str,
+        glossary: &[(&str, &str)],
+    ) -> Result<String, PromptError> {
+        let mut sys_msg = format!(
+            "Translate the text given by the user into {}. {}",
+            target_lang, PRESERVE_MARKUP
+        );
+        if !glossary.is_empty() {
+            sys_msg += "\nUse the following glossary for these terms:\n";
+            sys_msg += &glossary
+                .iter()
+                .map(|(src, dst)| format!("- {} => {}", src, dst))
+                .join("\n");
+        }
+        self.transform_chunks(&sys_msg, text, "translate").await
+    }
+
+    /// Rewrite `text` in the given style, chunking long inputs.
+    pub async fn rewrite(&self, text: &str, style: &str) -> Result<String, PromptError> {
+        let sys_msg = format!(
+            "Rewrite the text given by the user in the following style: {}. Keep the meaning unchanged. {}",
+            style, PRESERVE_MARKUP
+        );
+        self.transform_chunks(&sys_msg, text, "rewrite").await
+    }
+
+    async fn transform_chunks(
+        &self,
+        sys_msg: &str,
+        text: &str,
+        prefix: &str,
+    ) -> Result<String, PromptError> {
+        let chunks = chunk_text(text, CHUNK_CHARS);
+        let outputs: Vec<String> = stream::iter(chunks.iter())
+            .map(|chunk| async move {
+                let (body, trailing) = split_trailing_whitespace(chunk);
+                if body.trim().is_empty() {
+                    return Ok(chunk.clone());
+                }
+                let resp = self
+                    .prompt_once_with_retry(sys_msg, body, Some(prefix), None)
+                    .await?;
+                Ok::<_, PromptError>(response_content(&resp)?.trim_end().to_string() + trailing)
+            })
+            .buffered(CHUNK_CONCURRENCY)
+            .try_collect()
+            .await?;
+        Ok(outputs.concat())
+    }
+}
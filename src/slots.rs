@@ -0,0 +1,151 @@
+use std::marker::PhantomData;
+
+use async_openai::types::chat::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+
+use crate::{error::PromptError, llm::LLMInner};
+
+/// What [`SlotFiller::turn`] wants next.
+#[derive(Debug, Clone)]
+pub enum SlotTurn<T> {
+    /// Show the question to the user and pass the answer to the next turn.
+    Ask(String),
+    Done(T),
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct SlotReply {
+    /// Values the user gave in their last message, by field name.
+    slots: Map<String, Value>,
+    /// Question asking the user for the missing or invalid fields, null if none.
+    question: Option<String>,
+}
+
+/// Fills the fields of `T` over several turns of a dialogue: the model extracts
+/// values from each user message and asks for what is still missing, until the
+/// collected values deserialize into `T`.
+#[derive(Debug)]
+pub struct SlotFiller<'a, T> {
+    llm: &'a LLMInner,
+    schema: Value,
+    required: Vec<String>,
+    filled: Map<String, Value>,
+    dialogue: Vec<ChatCompletionRequestMessage>,
+    invalid: Option<String>,
+    _t: PhantomData<T>,
+}
+
+impl<'a, T: JsonSchema + DeserializeOwned> SlotFiller<'a, T> {
+    pub fn new(llm: &'a LLMInner) -> Self {
+        let schema = serde_json::to_value(schemars::schema_for!(T)).unwrap_or_default();
+        let required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|fields| {
+                fields
+                    .iter()
+                    .filter_map(|f| f.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            llm,
+            schema,
+            required,
+            filled: Map::new(),
+            dialogue: vec![],
+            invalid: None,
+            _t: PhantomData,
+        }
+    }
+
+    pub fn filled(&self) -> &Map<String, Value> {
+        &self.filled
+    }
+
+    /// Required fields without a value yet.
+    pub fn missing(&self) -> Vec<&str> {
+        self.required
+            .iter()
+            .filter(|f| self.filled.get(*f).is_none_or(Value::is_null))
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn system_message(&self) -> String {
+        let mut msg = format!(
+            "You are filling a form with the user. The form follows this JSON schema:\n{}\n\
+            Values collected so far:\n{}\n\
+            Missing required fields: {}.\n\
+            Respond with a JSON object: \"slots\" holds the field values found in the last \
+            user message, \"question\" asks the user for the missing fields, or is null when \
+            nothing is missing.",
+            self.schema,
+            Value::Object(self.filled.clone()),
+            self.missing().join(", "),
+        );
+        if let Some(invalid) = self.invalid.as_ref() {
+            msg += &format!(
+                "\nThe collected values are invalid: {}. Ask the user to correct them.",
+                invalid
+            );
+        }
+        msg
+    }
+
+    /// Feed the next user message, returning either a question for the user or the
+    /// completed `T`.
+    pub async fn turn(&mut self, user_msg: &str) -> Result<SlotTurn<T>, PromptError> {
+        self.dialogue.push(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(user_msg)
+                .build()?
+                .into(),
+        );
+        let mut messages = vec![
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(self.system_message())
+                .build()?
+                .into(),
+        ];
+        messages.extend(self.dialogue.iter().cloned());
+
+        let reply: SlotReply = self
+            .llm
+            .complete_json(messages, Some("slots"), None)
+            .await?;
+        for (field, value) in reply.slots {
+            if !value.is_null() {
+                self.filled.insert(field, value);
+            }
+        }
+
+        if self.missing().is_empty() {
+            match serde_json::from_value::<T>(Value::Object(self.filled.clone())) {
+                Ok(t) => {
+                    self.invalid = None;
+                    return Ok(SlotTurn::Done(t));
+                }
+                Err(e) => self.invalid = Some(e.to_string()),
+            }
+        }
+
+        let question = match (reply.question, self.invalid.as_ref()) {
+            (Some(question), _) => question,
+            (None, Some(invalid)) => format!("Some values are invalid: {}", invalid),
+            (None, None) => format!("Please provide: {}", self.missing().join(", ")),
+        };
+        self.dialogue.push(
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .content(question.clone())
+                .build()?
+                .into(),
+        );
+        Ok(SlotTurn::Ask(question))
+    }
+}
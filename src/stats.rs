@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+
+use async_openai::types::chat::{
+    CompletionUsage, CreateChatCompletionRequest, CreateChatCompletionResponse, FinishReason,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{llm::response_to_string, tokens::TokenCounter};
+
+/// How often responses ended with each finish reason.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FinishReasonStats {
+    pub stop: u64,
+    pub length: u64,
+    pub tool_calls: u64,
+    pub content_filter: u64,
+    pub function_call: u64,
+    /// No finish reason reported, e.g. an interrupted stream.
+    pub unknown: u64,
+}
+
+impl FinishReasonStats {
+    pub fn record(&mut self, reason: Option<&FinishReason>) {
+        match reason {
+            Some(FinishReason::Stop) => self.stop += 1,
+            Some(FinishReason::Length) => self.length += 1,
+            Some(FinishReason::ToolCalls) => self.tool_calls += 1,
+            Some(FinishReason::ContentFilter) => self.content_filter += 1,
+            Some(FinishReason::FunctionCall) => self.function_call += 1,
+            None => self.unknown += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.stop
+            + self.length
+            + self.tool_calls
+            + self.content_filter
+            + self.function_call
+            + self.unknown
+    }
+
+    pub fn length_ratio(&self) -> f64 {
+        let total = self.total();
+        if total == 0 {
+            0.0
+        } else {
+            self.length as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LLMStats {
+    /// Keyed by model, then by prefix.
+    pub finish_reasons: BTreeMap<String, BTreeMap<String, FinishReasonStats>>,
+}
+
+impl LLMStats {
+    pub fn finish_reasons_of(&mut self, model: &str, prefix: &str) -> &mut FinishReasonStats {
+        self.finish_reasons
+            .entry(model.to_string())
+            .or_default()
+            .entry(prefix.to_string())
+            .or_default()
+    }
+}
+
+/// Size of one dumped completion, stored as a line of the debug stats file next to
+/// the dumps.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DumpStats {
+    pub index: u64,
+    pub prefix: String,
+    pub request_bytes: usize,
+    /// Messages counted by the token counter of the LLM.
+    pub request_tokens: usize,
+    pub response_bytes: usize,
+    pub response_tokens: usize,
+    /// Reported by the provider, None without usage.
+    pub prompt_tokens: Option<u32>,
+    pub cached_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+}
+
+impl DumpStats {
+    pub fn request(
+        index: u64,
+        prefix: &str,
+        req: &CreateChatCompletionRequest,
+        counter: &dyn TokenCounter,
+    ) -> Self {
+        Self {
+            index,
+            prefix: prefix.to_string(),
+            request_bytes: serde_json::to_string(req)
+                .map(|s| s.len())
+                .unwrap_or_default(),
+            request_tokens: counter.count_messages(&req.messages),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(
+        &mut self,
+        resp: &CreateChatCompletionResponse,
+        usage: Option<&CompletionUsage>,
+        counter: &dyn TokenCounter,
+    ) {
+        self.response_bytes = serde_json::to_string(resp)
+            .map(|s| s.len())
+            .unwrap_or_default();
+        self.response_tokens = resp
+            .choices
+            .iter()
+            .map(|c| counter.count(&response_to_string(&c.message)))
+            .sum();
+        self.prompt_tokens = usage.map(|u| u.prompt_tokens);
+        self.cached_tokens = usage
+            .and_then(|u| u.prompt_tokens_details.as_ref())
+            .and_then(|d| d.cached_tokens);
+        self.completion_tokens = usage.map(|u| u.completion_tokens);
+    }
+
+    /// Prompt tokens as reported, falling back to the counted ones.
+    pub fn prompt(&self) -> u64 {
+        self.prompt_tokens
+            .map(|t| t as u64)
+            .unwrap_or(self.request_tokens as u64)
+    }
+}
+
+/// One turn of a conversation in [`context_growth`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TurnGrowth {
+    pub index: u64,
+    pub prompt_tokens: u64,
+    /// Prompt tokens added since the previous turn of the same prefix.
+    pub growth: i64,
+    /// Share of the prompt served from the cache.
+    pub cache_hit: f64,
+}
+
+/// Context growth per turn, turns are grouped by prefix in index order.
+pub fn context_growth(stats: &[DumpStats]) -> BTreeMap<String, Vec<TurnGrowth>> {
+    let mut turns: BTreeMap<String, Vec<TurnGrowth>> = BTreeMap::new();
+    for s in stats.iter().sorted_by_key(|s| s.index) {
+        let prefix = turns.entry(s.prefix.clone()).or_default();
+        let prompt_tokens = s.prompt();
+        let growth = prompt_tokens as i64
+            - prefix
+                .last()
+                .map(|t| t.prompt_tokens as i64)
+                .unwrap_or_default();
+        let cache_hit = match prompt_tokens {
+            0 => 0.0,
+            p => s.cached_tokens.unwrap_or_default() as f64 / p as f64,
+        };
+        prefix.push(TurnGrowth {
+            index: s.index,
+            prompt_tokens,
+            growth,
+            cache_hit,
+        });
+    }
+    turns
+}
@@ -0,0 +1,338 @@
+use async_openai::types::chat::{
+    ChatChoice, ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+    CreateChatCompletionResponse, ResponseFormat, ResponseFormatJsonSchema,
+};
+use color_eyre::eyre::{OptionExt, eyre};
+use futures_util::{StreamExt, stream};
+use log::warn;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::json;
+
+use crate::{
+    error::PromptError,
+    llm::{LLMInner, LLMSettings},
+};
+
+/// `response_format` constraining the output to the json schema of `T`.
+pub fn json_schema_format<T: JsonSchema>() -> ResponseFormat {
+    let mut schema = serde_json::to_value(schemars::schema_for!(T)).unwrap_or_default();
+    if let Some(obj) = schema.as_object_mut() {
+        obj.remove("$schema");
+    }
+    let name = T::schema_name()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect::<String>();
+
+    ResponseFormat::JsonSchema {
+        json_schema: ResponseFormatJsonSchema {
+            description: None,
+            name,
+            schema: Some(schema),
+            strict: Some(false),
+        },
+    }
+}
+
+/// Result of [`LLMInner::classify`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Label {
+    pub label: String,
+    /// Joint probability of the label tokens, only when logprobs are requested.
+    pub confidence: Option<f64>,
+}
+
+fn label_format(labels: &[&str]) -> ResponseFormat {
+    ResponseFormat::JsonSchema {
+        json_schema: ResponseFormatJsonSchema {
+            description: None,
+            name: "label".to_string(),
+            schema: Some(json!({
+                "type": "object",
+                "properties": {
+                    "label": {
+                        "type": "string",
+                        "enum": labels,
+                    }
+                },
+                "required": ["label"],
+                "additionalProperties": false,
+            })),
+            strict: Some(true),
+        },
+    }
+}
+
+// Byte range of the `label` value in the content, past the `"label":` key so a label
+// spelled like the key is not mistaken for the value.
+fn label_span(content: &str, label: &str) -> Option<(usize, usize)> {
+    let key = content.find("\"label\"")? + "\"label\"".len();
+    let after_colon = content[key..].trim_start().strip_prefix(':')?;
+    let value = after_colon.trim_start();
+    let quoted = serde_json::to_string(label).ok()?;
+    if !value.starts_with(&quoted) {
+        return None;
+    }
+    let start = content.len() - value.len() + 1;
+    Some((start, start + quoted.len() - 2))
+}
+
+// Sum logprobs of the tokens overlapping the label value in the content.
+fn label_confidence(choice: &ChatChoice, content: &str, label: &str) -> Option<f64> {
+    let tokens = choice.logprobs.as_ref()?.content.as_ref()?;
+    let (start, end) = label_span(content, label)?;
+
+    let mut offset = 0;
+    let mut logprob = 0.0f64;
+    for tk in tokens.iter() {
+        let tk_end = offset + tk.token.len();
+        if tk_end > start && offset < end {
+            logprob += tk.logprob as f64;
+        }
+        offset = tk_end;
+    }
+    Some(logprob.exp())
+}
+
+const CLASSIFY_CONCURRENCY: usize = 8;
+
+pub fn response_content(resp: &CreateChatCompletionResponse) -> Result<String, PromptError> {
+    Ok(resp
+        .choices
+        .first()
+        .ok_or_eyre(eyre!("no choices"))?
+        .message
+        .content
+        .clone()
+        .ok_or_eyre(eyre!("no content"))?)
+}
+
+impl LLMInner {
+    /// Pull structured data of type `T` out of `text` in one call. Responses failing
+    /// to parse are fed back to the model and retried up to `llm_retry` times.
+    pub async fn extract<T: JsonSchema + DeserializeOwned>(
+        &self,
+        text: &str,
+        instructions: Option<&str>,
+    ) -> Result<T, PromptError> {
+        let mut sys_msg = "Extract structured data from the text given by the user. \
+            Respond with a single JSON object following the provided schema."
+            .to_string();
+        if let Some(instructions) = instructions {
+            sys_msg += "\n";
+            sys_msg += instructions;
+        }
+
+        self.prompt_json(&sys_msg, text, Some("extract"), None)
+            .await
+    }
+
+    /// Prompt with a `response_format` json schema derived from `T` and parse the
+    /// response into `T`. Parse errors are fed back to the model and retried up to
+    /// `llm_retry` times.
+    pub async fn prompt_json<T: JsonSchema + DeserializeOwned>(
+        &self,
+        sys_msg: &str,
+        user_msg: &str,
+        prefix: Option<&str>,
+        settings: Option<LLMSettings>,
+    ) -> Result<T, PromptError> {
+        let messages = vec![
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(sys_msg)
+                .build()?
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(user_msg)
+                .build()?
+                .into(),
+        ];
+        self.complete_json(messages, prefix, settings).await
+    }
+
+    pub(crate) async fn complete_json<T: JsonSchema + DeserializeOwned>(
+        &self,
+        mut messages: Vec<ChatCompletionRequestMessage>,
+        prefix: Option<&str>,
+        settings: Option<LLMSettings>,
+    ) -> Result<T, PromptError> {
+        let settings = settings.unwrap_or_else(|| self.default_settings.clone());
+        let mut last = None;
+        for idx in 0..settings.llm_retry.max(1) {
+            let req = self
+                .request_args(&settings, prefix)
+                .messages(messages.clone())
+                .response_format(json_schema_format::<T>())
+                .build()?;
+            let resp = self.complete_with_settings(&req, prefix, &settings).await?;
+            let content = response_content(&resp)?;
+
+            match serde_json::from_str::<T>(&content) {
+                Ok(t) => return Ok(t),
+                Err(e) => {
+                    warn!("Fail to parse structured output at {} retry: {}", idx, e);
+                    messages.push(
+                        ChatCompletionRequestAssistantMessageArgs::default()
+                            .content(content)
+                            .build()?
+                            .into(),
+                    );
+                    messages.push(
+                        ChatCompletionRequestUserMessageArgs::default()
+                            .content(format!(
+                                "Your response is not valid: {}. Respond again with JSON following the schema.",
+                                e
+                            ))
+                            .build()?
+                            .into(),
+                    );
+                    last = Some(e);
+                }
+            }
+        }
+
+        Err(last
+            .map(PromptError::STDJSON)
+            .unwrap_or_else(|| PromptError::Other(eyre!("retry is zero?!"))))
+    }
+
+    /// Classify `text` into one of `labels` using a constrained enum output.
+    pub async fn classify(&self, text: &str, labels: &[&str]) -> Result<Label, PromptError> {
+        self.classify_inner(text, labels, false).await
+    }
+
+    /// Same as [`Self::classify`] but requests logprobs to fill [`Label::confidence`].
+    pub async fn classify_calibrated(
+        &self,
+        text: &str,
+        labels: &[&str],
+    ) -> Result<Label, PromptError> {
+        self.classify_inner(text, labels, true).await
+    }
+
+    /// Classify many texts concurrently, results are aligned with `texts`.
+    pub async fn classify_batch(
+        &self,
+        texts: &[&str],
+        labels: &[&str],
+        calibrated: bool,
+    ) -> Vec<Result<Label, PromptError>> {
+        stream::iter(texts.iter())
+            .map(|text| self.classify_inner(text, labels, calibrated))
+            .buffered(CLASSIFY_CONCURRENCY)
+            .collect()
+            .await
+    }
+
+    async fn classify_inner(
+        &self,
+        text: &str,
+        labels: &[&str],
+        logprobs: bool,
+    ) -> Result<Label, PromptError> {
+        let settings = self.default_settings.clone();
+        let sys_msg = format!(
+            "Classify the text given by the user into exactly one of the following labels: {}. \
+            Respond with a JSON object holding the chosen label.",
+            labels.join(", ")
+        );
+        let mut req = self.request_args(&settings, Some("classify"));
+        req.messages(vec![
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(sys_msg)
+                .build()?
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(text)
+                .build()?
+                .into(),
+        ])
+        .response_format(label_format(labels));
+        if logprobs {
+            req.logprobs(true);
+        }
+        let req = req.build()?;
+
+        let resp = self
+            .complete_with_settings(&req, Some("classify"), &settings)
+            .await?;
+        let content = response_content(&resp)?;
+
+        #[derive(Deserialize)]
+        struct Chosen {
+            label: String,
+        }
+        let chosen: Chosen = serde_json::from_str(&content)?;
+        if !labels.contains(&chosen.label.as_str()) {
+            return Err(PromptError::Other(eyre!(
+                "model chose unknown label {}",
+                chosen.label
+            )));
+        }
+
+        let confidence = if logprobs {
+            resp.choices
+                .first()
+                .and_then(|ch| label_confidence(ch, &content, &chosen.label))
+        } else {
+            None
+        };
+
+        Ok(Label {
+            label: chosen.label,
+            confidence,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_span() {
+        let content = r#"{"label": "label"}"#;
+        let (start, end) = label_span(content, "label").unwrap();
+        assert_eq!(start, 11);
+        assert_eq!(&content[start..end], "label");
+
+        let content = r#"{ "label" :"spam"}"#;
+        let (start, end) = label_span(content, "spam").unwrap();
+        assert_eq!(&content[start..end], "spam");
+
+        let content = r#"{"label":"say \"hi\""}"#;
+        let (start, end) = label_span(content, "say \"hi\"").unwrap();
+        assert_eq!(&content[start..end], r#"say \"hi\""#);
+
+        assert_eq!(label_span(r#"{"label": "ham"}"#, "spam"), None);
+    }
+
+    #[test]
+    fn test_label_confidence() {
+        let choice: ChatChoice = serde_json::from_value(json!({
+            "index": 0,
+            "message": {"role": "assistant", "content": "{\"label\":\"label\"}"},
+            "finish_reason": "stop",
+            "logprobs": {"content": [
+                {"token": "{\"", "logprob": 0.0, "bytes": null, "top_logprobs": []},
+                {"token": "label", "logprob": -2.0, "bytes": null, "top_logprobs": []},
+                {"token": "\":\"", "logprob": 0.0, "bytes": null, "top_logprobs": []},
+                {"token": "label", "logprob": -0.5, "bytes": null, "top_logprobs": []},
+                {"token": "\"}", "logprob": 0.0, "bytes": null, "top_logprobs": []},
+            ]},
+        }))
+        .unwrap();
+        let content = choice.message.content.clone().unwrap();
+        let confidence = label_confidence(&choice, &content, "label").unwrap();
+        assert!((confidence - (-0.5f64).exp()).abs() < 1e-9);
+    }
+}
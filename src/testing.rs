@@ -0,0 +1,179 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use async_openai::{
+    error::OpenAIError,
+    types::{
+        chat::{
+            ChatCompletionRequestUserMessageArgs, ChatCompletionResponseStream,
+            CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+            CreateChatCompletionResponse, CreateChatCompletionStreamResponse,
+        },
+        embeddings::{CreateEmbeddingRequest, CreateEmbeddingResponse, EmbeddingInput},
+    },
+};
+use clap::Parser;
+use futures_util::{FutureExt, StreamExt, future::BoxFuture, stream};
+use reqwest::header::HeaderMap;
+use serde_json::json;
+
+use crate::{
+    backend::ChatBackend,
+    error::PromptError,
+    llm::{LLM, LLMClient, OpenAISetup},
+};
+
+#[derive(Parser)]
+struct Cli {
+    #[command(flatten)]
+    setup: OpenAISetup,
+}
+
+/// An LLM on `backend`, `args` are extra command line flags.
+pub(crate) fn mock_llm(backend: Arc<dyn ChatBackend>, args: &[&str]) -> LLM {
+    let cli = Cli::parse_from(["test", "--model", "gpt-4o"].iter().chain(args));
+    cli.setup.to_llm_with(LLMClient::Custom(backend))
+}
+
+/// A fresh empty directory under the system temp dir.
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "openai-models-{}-{}-{:016x}",
+        name,
+        std::process::id(),
+        fastrand::u64(..)
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub(crate) fn user_request(content: &str) -> CreateChatCompletionRequest {
+    CreateChatCompletionRequestArgs::default()
+        .model("gpt-4o")
+        .messages(vec![
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(content)
+                .build()
+                .unwrap()
+                .into(),
+        ])
+        .build()
+        .unwrap()
+}
+
+pub(crate) fn response(content: &str) -> CreateChatCompletionResponse {
+    serde_json::from_value(json!({
+        "id": "resp",
+        "object": "chat.completion",
+        "created": 1,
+        "model": "gpt-4o",
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": content},
+            "finish_reason": "stop",
+        }],
+        "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+    }))
+    .unwrap()
+}
+
+pub(crate) fn chunk(content: Option<&str>, finish: bool) -> CreateChatCompletionStreamResponse {
+    serde_json::from_value(json!({
+        "id": "resp",
+        "object": "chat.completion.chunk",
+        "created": 1,
+        "model": "gpt-4o",
+        "choices": [{
+            "index": 0,
+            "delta": {"role": "assistant", "content": content},
+            "finish_reason": finish.then_some("stop"),
+        }],
+    }))
+    .unwrap()
+}
+
+/// Answers every chat with `pieces`, streamed one piece per chunk, and embeds each
+/// input as `[input length]`.
+#[derive(Debug, Default)]
+pub(crate) struct MockBackend {
+    pub pieces: Vec<String>,
+    pub requests: Mutex<Vec<CreateChatCompletionRequest>>,
+    pub embed_batches: Mutex<Vec<usize>>,
+    /// Fail every embedding request.
+    pub embed_fails: bool,
+}
+
+impl MockBackend {
+    pub fn new(pieces: &[&str]) -> Self {
+        Self {
+            pieces: pieces.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl ChatBackend for MockBackend {
+    fn create_chat(
+        &self,
+        req: CreateChatCompletionRequest,
+        _headers: HeaderMap,
+    ) -> BoxFuture<'_, Result<CreateChatCompletionResponse, PromptError>> {
+        self.requests.lock().unwrap().push(req);
+        let resp = response(&self.pieces.concat());
+        async move { Ok(resp) }.boxed()
+    }
+
+    fn create_chat_stream(
+        &self,
+        req: CreateChatCompletionRequest,
+        _headers: HeaderMap,
+    ) -> BoxFuture<'_, Result<ChatCompletionResponseStream, PromptError>> {
+        self.requests.lock().unwrap().push(req);
+        let mut chunks: Vec<_> = self
+            .pieces
+            .iter()
+            .enumerate()
+            .map(|(idx, piece)| Ok(chunk(Some(piece), idx + 1 == self.pieces.len())))
+            .collect();
+        let mut usage = chunk(None, false);
+        usage.choices.clear();
+        usage.usage = response("").usage;
+        chunks.push(Ok(usage));
+        async move { Ok(stream::iter(chunks).boxed()) }.boxed()
+    }
+
+    fn create_embedding(
+        &self,
+        req: CreateEmbeddingRequest,
+    ) -> BoxFuture<'_, Result<CreateEmbeddingResponse, OpenAIError>> {
+        let EmbeddingInput::StringArray(inputs) = req.input else {
+            unreachable!("embed sends string arrays");
+        };
+        self.embed_batches.lock().unwrap().push(inputs.len());
+        let fails = self.embed_fails;
+        async move {
+            if fails {
+                return Err(OpenAIError::InvalidArgument("mock failure".to_string()));
+            }
+            // Reversed to check that vectors are put back in input order.
+            let data: Vec<_> = inputs
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(idx, input)| {
+                    json!({"object": "embedding", "index": idx, "embedding": [input.len() as f32]})
+                })
+                .collect();
+            Ok(serde_json::from_value(json!({
+                "object": "list",
+                "model": req.model,
+                "data": data,
+                "usage": {"prompt_tokens": inputs.len(), "total_tokens": inputs.len()},
+            }))
+            .unwrap())
+        }
+        .boxed()
+    }
+}
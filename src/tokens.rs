@@ -0,0 +1,259 @@
+use std::fmt::Debug;
+
+use async_openai::types::chat::{
+    ChatCompletionRequestMessage, ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart, ChatCompletionTools, CreateChatCompletionRequest,
+    ImageDetail, ImageUrl,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use log::{debug, warn};
+use tiktoken_rs::CoreBPE;
+
+use crate::{OpenAIModel, llm::completion_to_string};
+
+/// Counts tokens of prompts, implement it for tokenizers of models served behind
+/// compatible endpoints (Llama, Qwen...).
+pub trait TokenCounter: Debug + Send + Sync {
+    fn count(&self, text: &str) -> usize;
+
+    /// Image parts are counted with [`image_url_tokens`] instead of their url.
+    fn count_messages(&self, messages: &[ChatCompletionRequestMessage]) -> usize {
+        messages
+            .iter()
+            .map(|msg| match msg {
+                ChatCompletionRequestMessage::User(usr) => {
+                    let ChatCompletionRequestUserMessageContent::Array(parts) = &usr.content else {
+                        return self.count(&completion_to_string(msg));
+                    };
+                    let (images, others): (Vec<_>, Vec<_>) = parts.iter().partition(|p| {
+                        matches!(p, ChatCompletionRequestUserMessageContentPart::ImageUrl(_))
+                    });
+                    let mut usr = usr.clone();
+                    usr.content = ChatCompletionRequestUserMessageContent::Array(
+                        others.into_iter().cloned().collect(),
+                    );
+                    let images: usize = images
+                        .into_iter()
+                        .map(|p| match p {
+                            ChatCompletionRequestUserMessageContentPart::ImageUrl(img) => {
+                                image_url_tokens(&img.image_url)
+                            }
+                            _ => 0,
+                        })
+                        .sum();
+                    self.count(&completion_to_string(&usr.into())) + images
+                }
+                _ => self.count(&completion_to_string(msg)),
+            })
+            .sum()
+    }
+
+    /// Tool definitions are counted as their json schema.
+    fn count_tools(&self, tools: &[ChatCompletionTools]) -> usize {
+        if tools.is_empty() {
+            return 0;
+        }
+        match serde_json::to_string(tools) {
+            Ok(tools) => self.count(&tools),
+            Err(e) => {
+                warn!("Fail to serialize tools due to {}", e);
+                0
+            }
+        }
+    }
+}
+
+// Assumed size of images whose dimensions are unknown, e.g. remote urls.
+const DEFAULT_IMAGE_SIDE: u64 = 1024;
+
+/// Tokens of a `width`x`height` image for GPT-4o class models: 85 at low detail,
+/// otherwise 85 plus 170 per 512px tile after fitting in 2048x2048 and scaling the
+/// shortest side down to 768.
+pub fn image_tokens(width: u64, height: u64, detail: &ImageDetail) -> usize {
+    const BASE: usize = 85;
+    const PER_TILE: usize = 170;
+    if matches!(detail, ImageDetail::Low) || width == 0 || height == 0 {
+        return BASE;
+    }
+
+    let (mut w, mut h) = (width as f64, height as f64);
+    if w.max(h) > 2048.0 {
+        let scale = 2048.0 / w.max(h);
+        w *= scale;
+        h *= scale;
+    }
+    if w.min(h) > 768.0 {
+        let scale = 768.0 / w.min(h);
+        w *= scale;
+        h *= scale;
+    }
+    let tiles = (w / 512.0).ceil() as usize * (h / 512.0).ceil() as usize;
+    BASE + PER_TILE * tiles
+}
+
+// Dimensions of a base64 data url image.
+fn data_url_dimensions(url: &str) -> Option<(u64, u64)> {
+    let (_, data) = url.strip_prefix("data:")?.split_once(";base64,")?;
+    let bytes = STANDARD.decode(data.trim()).ok()?;
+    let size = imagesize::blob_size(&bytes).ok()?;
+    Some((size.width as u64, size.height as u64))
+}
+
+/// Tokens of an image part, dimensions are read from data urls and assumed
+/// 1024x1024 for remote urls.
+pub fn image_url_tokens(image: &ImageUrl) -> usize {
+    let (width, height) = data_url_dimensions(&image.url).unwrap_or_else(|| {
+        debug!(
+            "Unknown image dimensions, assuming {}px",
+            DEFAULT_IMAGE_SIDE
+        );
+        (DEFAULT_IMAGE_SIDE, DEFAULT_IMAGE_SIDE)
+    });
+    image_tokens(
+        width,
+        height,
+        image.detail.as_ref().unwrap_or(&ImageDetail::Auto),
+    )
+}
+
+/// Assume 4 characters per token.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicCounter;
+
+impl TokenCounter for HeuristicCounter {
+    fn count(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
+
+#[derive(Clone)]
+pub struct TiktokenCounter {
+    bpe: &'static CoreBPE,
+}
+
+impl Debug for TiktokenCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TiktokenCounter").finish_non_exhaustive()
+    }
+}
+
+impl TiktokenCounter {
+    /// The tokenizer of `model`, None if tiktoken doesn't know it.
+    pub fn for_model(model: &OpenAIModel) -> Option<Self> {
+        tiktoken_rs::bpe_for_model(&model.to_string())
+            .ok()
+            .map(|bpe| Self { bpe })
+    }
+
+    pub fn o200k() -> Self {
+        Self {
+            bpe: tiktoken_rs::o200k_base_singleton(),
+        }
+    }
+}
+
+impl TokenCounter for TiktokenCounter {
+    fn count(&self, text: &str) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+}
+
+/// Tiktoken for models it knows, the chars/4 heuristic for the others.
+pub fn default_counter(model: &OpenAIModel) -> Box<dyn TokenCounter> {
+    match TiktokenCounter::for_model(model) {
+        Some(counter) => Box::new(counter),
+        None => Box::new(HeuristicCounter),
+    }
+}
+
+/// Tokens of `text` with the tokenizer of `model`.
+pub fn count_tokens(model: &OpenAIModel, text: &str) -> usize {
+    default_counter(model).count(text)
+}
+
+// Chat formatting overhead of OpenAI models: each message is wrapped in a few
+// tokens and the reply is primed with a few more.
+const TOKENS_PER_MESSAGE: usize = 3;
+const TOKENS_REPLY_PRIMING: usize = 3;
+
+/// Prompt tokens of `req` with the tokenizer of its model: messages, chat formatting
+/// and tool definitions. An estimate, the billed count may differ slightly.
+pub fn count_request_tokens(req: &CreateChatCompletionRequest) -> usize {
+    let model = req.model.parse().unwrap_or(OpenAIModel::GPT4O);
+    let counter = default_counter(&model);
+    counter.count_messages(&req.messages)
+        + TOKENS_PER_MESSAGE * req.messages.len()
+        + TOKENS_REPLY_PRIMING
+        + counter.count_tools(req.tools.as_deref().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1x1 transparent png
+    const PIXEL: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==";
+
+    fn image(url: &str, detail: Option<ImageDetail>) -> ImageUrl {
+        ImageUrl {
+            url: url.to_string(),
+            detail,
+        }
+    }
+
+    #[test]
+    fn test_image_tokens() {
+        assert_eq!(image_tokens(1024, 1024, &ImageDetail::Low), 85);
+        assert_eq!(image_tokens(0, 0, &ImageDetail::High), 85);
+        assert_eq!(image_tokens(512, 512, &ImageDetail::High), 255);
+        // 768x768 after scaling, 2x2 tiles
+        assert_eq!(image_tokens(1024, 1024, &ImageDetail::Auto), 765);
+        // 1024x2048 to fit 2048, then 768x1536, 2x3 tiles
+        assert_eq!(image_tokens(2048, 4096, &ImageDetail::High), 1105);
+    }
+
+    #[test]
+    fn test_image_url_tokens() {
+        assert_eq!(image_url_tokens(&image(PIXEL, None)), 255);
+        assert_eq!(image_url_tokens(&image(PIXEL, Some(ImageDetail::Low))), 85);
+        // Remote images are assumed 1024x1024.
+        assert_eq!(
+            image_url_tokens(&image("https://example.com/a.png", None)),
+            765
+        );
+        assert_eq!(data_url_dimensions(PIXEL), Some((1, 1)));
+        assert_eq!(data_url_dimensions("data:image/png;base64,!!"), None);
+    }
+
+    #[test]
+    fn test_count_messages_with_image() {
+        let with_image: Vec<ChatCompletionRequestMessage> =
+            serde_json::from_value(serde_json::json!([{"role": "user", "content": [
+                {"type": "text", "text": "what is it"},
+                {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+            ]}]))
+            .unwrap();
+        let text_only: Vec<ChatCompletionRequestMessage> =
+            serde_json::from_value(serde_json::json!([{"role": "user", "content": [
+                {"type": "text", "text": "what is it"},
+            ]}]))
+            .unwrap();
+        let counter = HeuristicCounter;
+        assert_eq!(
+            counter.count_messages(&with_image),
+            counter.count_messages(&text_only) + 765
+        );
+    }
+
+    #[test]
+    fn test_count_tools() {
+        let counter = HeuristicCounter;
+        assert_eq!(counter.count_tools(&[]), 0);
+        let tools: Vec<ChatCompletionTools> = serde_json::from_value(serde_json::json!([
+            {"type": "function", "function": {"name": "weather", "parameters": {"type": "object"}}},
+        ]))
+        .unwrap();
+        let schema = serde_json::to_string(&tools).unwrap();
+        assert_eq!(counter.count_tools(&tools), counter.count(&schema));
+    }
+}
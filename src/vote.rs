@@ -0,0 +1,196 @@
+use std::cmp::Reverse;
+
+use async_openai::types::chat::{
+    ChatCompletionMessageToolCalls, CreateChatCompletionRequest, CreateChatCompletionResponse,
+};
+use color_eyre::eyre::eyre;
+use futures_util::future::join_all;
+use itertools::Itertools;
+use log::{info, warn};
+
+use crate::{error::PromptError, llm::LLMInner};
+
+#[derive(Debug, Clone)]
+pub struct VoteOptions {
+    /// How many times the same turn is requested.
+    pub samples: usize,
+    /// Temperatures cycled through the samples, the request one if empty.
+    pub temperatures: Vec<f32>,
+}
+
+impl Default for VoteOptions {
+    fn default() -> Self {
+        Self {
+            samples: 3,
+            temperatures: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MajorityVote {
+    pub response: CreateChatCompletionResponse,
+    /// Samples agreeing with `response`.
+    pub votes: usize,
+    /// Samples that succeeded.
+    pub samples: usize,
+}
+
+// Tool calls with their arguments normalized, or the text content when there is none.
+fn vote_key(resp: &CreateChatCompletionResponse) -> String {
+    let Some(choice) = resp.choices.first() else {
+        return String::new();
+    };
+    match choice.message.tool_calls.as_ref() {
+        Some(calls) if !calls.is_empty() => calls
+            .iter()
+            .map(|call| match call {
+                ChatCompletionMessageToolCalls::Function(f) => {
+                    let args = serde_json::from_str::<serde_json::Value>(&f.function.arguments)
+                        .map(|v| v.to_string())
+                        .unwrap_or_else(|_| f.function.arguments.trim().to_string());
+                    format!("{}({})", f.function.name, args)
+                }
+                ChatCompletionMessageToolCalls::Custom(c) => {
+                    format!("{}({})", c.custom_tool.name, c.custom_tool.input.trim())
+                }
+            })
+            .join(";"),
+        _ => choice
+            .message
+            .content
+            .as_deref()
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+    }
+}
+
+// The first response of the largest group and its size. Ties go to the group
+// answered first in sample order.
+fn majority(
+    responses: Vec<CreateChatCompletionResponse>,
+) -> Option<(CreateChatCompletionResponse, usize)> {
+    let mut groups = responses
+        .into_iter()
+        .enumerate()
+        .into_group_map_by(|(_, resp)| vote_key(resp));
+    let key = groups
+        .iter()
+        .max_by_key(|(_, group)| (group.len(), Reverse(group[0].0)))
+        .map(|(key, _)| key.clone())?;
+    let mut group = groups.remove(&key)?;
+    let votes = group.len();
+    Some((group.swap_remove(0).1, votes))
+}
+
+impl LLMInner {
+    /// Request the same turn several times concurrently and return the response whose
+    /// tool calls (or content) the most samples agree on. Every sample is billed.
+    pub async fn complete_majority(
+        &self,
+        req: &CreateChatCompletionRequest,
+        prefix: Option<&str>,
+        options: &VoteOptions,
+    ) -> Result<MajorityVote, PromptError> {
+        let requests = (0..options.samples.max(1)).map(|idx| {
+            let mut req = req.clone();
+            if !options.temperatures.is_empty() {
+                req.temperature = Some(options.temperatures[idx % options.temperatures.len()]);
+            }
+            self.complete(req, prefix)
+        });
+
+        let mut last_err = None;
+        let mut responses = vec![];
+        for result in join_all(requests).await {
+            match result {
+                Ok(resp) => responses.push(resp),
+                Err(e) => {
+                    warn!("A voting sample failed with {}", e);
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        let samples = responses.len();
+        let Some((response, votes)) = majority(responses) else {
+            return Err(last_err.unwrap_or_else(|| PromptError::Other(eyre!("no sample to vote"))));
+        };
+        info!("Majority vote: {}/{} samples agree", votes, samples);
+
+        Ok(MajorityVote {
+            response,
+            votes,
+            samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::response;
+
+    fn tool_call(id: &str, arguments: &str) -> CreateChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": 1,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": format!("call_{}", id),
+                        "type": "function",
+                        "function": {"name": "search", "arguments": arguments},
+                    }],
+                },
+                "finish_reason": "tool_calls",
+            }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_vote_key_normalizes_arguments() {
+        let a = tool_call("a", r#"{"query": "rust", "limit": 3}"#);
+        let b = tool_call("b", "{\"limit\":3,\n  \"query\":\"rust\"}");
+        let c = tool_call("c", r#"{"query": "rust", "limit": 4}"#);
+        assert_eq!(vote_key(&a), vote_key(&b));
+        assert_ne!(vote_key(&a), vote_key(&c));
+        // Arguments that are not json are compared trimmed.
+        assert_eq!(
+            vote_key(&tool_call("d", " not json ")),
+            vote_key(&tool_call("e", "not json"))
+        );
+        assert_eq!(vote_key(&response("  yes\n")), "yes");
+    }
+
+    #[test]
+    fn test_majority_tie_break() {
+        let (resp, votes) = majority(vec![
+            response("no"),
+            response("yes"),
+            response("yes "),
+            response("no"),
+        ])
+        .unwrap();
+        assert_eq!(votes, 2);
+        assert_eq!(resp.choices[0].message.content.as_deref(), Some("no"));
+
+        let (resp, votes) = majority(vec![
+            tool_call("a", r#"{"query": "x", "limit": 1}"#),
+            response("maybe"),
+            tool_call("b", r#"{"limit": 1, "query": "x"}"#),
+        ])
+        .unwrap();
+        assert_eq!((resp.id.as_str(), votes), ("a", 2));
+        assert!(majority(vec![]).is_none());
+    }
+}